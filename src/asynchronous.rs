@@ -0,0 +1,141 @@
+//! The TAPCP operations over `tokio::net::UdpSocket`, for talking to lots of boards at once.
+//!
+//! These behave exactly like their blocking counterparts in the crate root. Each socket can only
+//! carry one transfer at a time, so use a socket per board to run them concurrently.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+use crate::{
+    protocol,
+    tftp::{self, Config, Mode},
+    Error,
+};
+
+/// Gets the temperature of the remote device in Celsius
+pub async fn temp(socket: &mut UdpSocket, addr: SocketAddr, config: &Config) -> Result<f32, Error> {
+    let bytes = tftp::read_async("/temp", socket, addr, Mode::Octet, config).await?;
+    protocol::decode_temp(&bytes)
+}
+
+/// Gets the list of top level commands (as a string)
+pub async fn help(
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<String, Error> {
+    let bytes = tftp::read_async("/help", socket, addr, Mode::NetASCII, config).await?;
+    protocol::decode_help(&bytes)
+}
+
+/// Gets the list of all devices supported by the currently running gateware
+/// Returns a hash map from device name to (addr,length)
+pub async fn listdev(
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<HashMap<String, (u32, u32)>, Error> {
+    let bytes = tftp::read_async("/listdev", socket, addr, Mode::Octet, config).await?;
+    protocol::decode_listdev(&bytes)
+}
+
+/// Look up the (addr,length) of a single device, without decoding the whole device list.
+/// Returns `None` if the gateware doesn't have it.
+pub async fn find_device(
+    device: &str,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Option<(u32, u32)>, Error> {
+    let bytes = tftp::read_async("/listdev", socket, addr, Mode::Octet, config).await?;
+    protocol::find_device(&bytes, device)
+}
+
+/// Find which device's memory contains the bus address `address`.
+/// Returns the name of the device and how many bytes into it `address` is.
+pub async fn device_at(
+    address: u32,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Option<(String, u32)>, Error> {
+    let bytes = tftp::read_async("/listdev", socket, addr, Mode::Octet, config).await?;
+    protocol::device_at(&bytes, address)
+}
+
+/// Read memory associated with the gateware device `device`
+/// We can read `offset` words (4 bytes) into a given device in multiples on `n` words
+/// The special case of `n` = 0 will read all the bytes at that location
+pub async fn read_device(
+    device: &str,
+    offset: usize,
+    n: usize,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let filename = protocol::read_device_filename(device, offset, n);
+    let bytes = tftp::read_async(&filename, socket, addr, Mode::Octet, config)
+        .await
+        .map_err(|e| protocol::device_error(device, e))?;
+    protocol::check_device_read(bytes, n)
+}
+
+/// Write bytes to the device named `device`
+pub async fn write_device(
+    device: &str,
+    offset: usize,
+    data: &[u8],
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let filename = protocol::write_device_filename(device, offset);
+    tftp::write_async(&filename, data, socket, addr, config)
+        .await
+        .map_err(|e| protocol::device_error(device, e))
+}
+
+/// Read memory from the onboard flash
+/// `offset` and `n` are in increments of 4 byte words, just like `read_device`
+pub async fn read_flash(
+    offset: usize,
+    n: usize,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let filename = protocol::read_flash_filename(offset, n);
+    Ok(tftp::read_async(&filename, socket, addr, Mode::Octet, config).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tftp::tests::{data, next_packet};
+    use std::thread;
+
+    #[test]
+    fn test_read_device() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            server.send_to(&data(1, 8), from).unwrap();
+            next_packet(&server);
+        });
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let bytes = rt
+            .block_on(async {
+                let mut socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                read_device("foo", 0, 2, &mut socket, addr, &Config::default()).await
+            })
+            .unwrap();
+        assert_eq!(bytes, vec![1; 8]);
+        handle.join().unwrap();
+    }
+}
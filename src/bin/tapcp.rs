@@ -0,0 +1,356 @@
+//! `tapcp`, a command-line tool for talking to a TAPCP board
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use tapcp::{flash::Progress, fpg::Fpg, Tapcp};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const USAGE: &str = "\
+Usage: tapcp --host HOST [--port PORT] [--timeout MS] [--retries N] COMMAND
+
+Commands:
+    temp                                  Print the board's temperature in Celsius
+    help                                  Print the board's list of commands
+    listdev [--json]                      List the gateware's devices
+    read DEVICE [OFFSET [N]] [--format hex|dec|raw] [--output FILE]
+                                          Read N words at word OFFSET into DEVICE
+    write DEVICE OFFSET (WORD... | --file FILE)
+                                          Write words, or the contents of FILE, to DEVICE
+    flash read OFFSET N [--output FILE]   Read N words at word OFFSET in flash
+    program FILE                          Flash an .fpg or raw bitstream and boot it
+
+Numbers can be decimal or 0x prefixed hex.";
+
+/// Options that don't take a value
+const FLAGS: &[&str] = &["json"];
+
+/// Options that take a value
+const OPTIONS: &[&str] = &[
+    "host", "port", "timeout", "retries", "format", "output", "file",
+];
+
+/// The command line, split into positional arguments and `--name value` options
+#[derive(Debug, Default)]
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                parsed.positional.push(arg);
+                continue;
+            };
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_owned())),
+                None => (name, None),
+            };
+            if !OPTIONS.contains(&name) && !FLAGS.contains(&name) {
+                return Err(format!("Unknown option --{}\n\n{}", name, USAGE).into());
+            }
+            let value = match value {
+                Some(value) => value,
+                None if FLAGS.contains(&name) => String::new(),
+                None => args
+                    .next()
+                    .ok_or_else(|| format!("--{} needs a value", name))?,
+            };
+            parsed.options.insert(name.to_owned(), value);
+        }
+        Ok(parsed)
+    }
+
+    /// Fail if there are more than `count` positional arguments, the command included
+    fn at_most(&self, count: usize) -> Result<()> {
+        match self.positional.get(count) {
+            Some(extra) => Err(format!("Unexpected argument {}\n\n{}", extra, USAGE).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    /// The positional argument at `index`, which is called `name` in error messages
+    fn positional(&self, index: usize, name: &str) -> Result<&str> {
+        self.positional
+            .get(index)
+            .map(String::as_str)
+            .ok_or_else(|| format!("Missing {}\n\n{}", name, USAGE).into())
+    }
+}
+
+fn parse_number(text: &str) -> Result<u64> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("{} isn't a number", text).into())
+}
+
+fn parse_word(text: &str) -> Result<u32> {
+    Ok(
+        u32::try_from(parse_number(text)?)
+            .map_err(|_| format!("{} doesn't fit in a word", text))?,
+    )
+}
+
+/// `text` as a JSON string literal
+fn json_string(text: &str) -> String {
+    let mut json = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => json += "\\\"",
+            '\\' => json += "\\\\",
+            c if c.is_control() => json += &format!("\\u{:04x}", c as u32),
+            c => json.push(c),
+        }
+    }
+    json + "\""
+}
+
+fn connect(args: &Args) -> Result<Tapcp> {
+    let host = args
+        .option("host")
+        .ok_or_else(|| format!("Missing --host\n\n{}", USAGE))?;
+    let mut builder = Tapcp::builder(host);
+    if let Some(port) = args.option("port") {
+        builder = builder
+            .port(u16::try_from(parse_number(port)?).map_err(|_| format!("Bad port {}", port))?);
+    }
+    if let Some(timeout) = args.option("timeout") {
+        let timeout = parse_number(timeout)?;
+        if timeout == 0 {
+            return Err(format!("--timeout must be at least 1 ms\n\n{}", USAGE).into());
+        }
+        builder = builder.timeout(Duration::from_millis(timeout));
+    }
+    if let Some(retries) = args.option("retries") {
+        builder = builder.retries(parse_number(retries)? as usize);
+    }
+    Ok(builder.build()?)
+}
+
+/// Print `bytes` as `format`, or write them raw to the file in `--output`
+fn output(bytes: &[u8], args: &Args) -> Result<()> {
+    if let Some(path) = args.option("output") {
+        fs::write(path, bytes)?;
+        return Ok(());
+    }
+    let mut stdout = io::stdout().lock();
+    match args.option("format").unwrap_or("hex") {
+        "raw" => stdout.write_all(bytes)?,
+        format @ ("hex" | "dec") => {
+            for word in bytes.chunks(4) {
+                let mut padded = [0; 4];
+                padded[..word.len()].copy_from_slice(word);
+                let word = u32::from_be_bytes(padded);
+                if format == "hex" {
+                    writeln!(stdout, "{:#010x}", word)?;
+                } else {
+                    writeln!(stdout, "{}", word)?;
+                }
+            }
+        }
+        format => return Err(format!("Unknown format {}", format).into()),
+    }
+    Ok(())
+}
+
+fn listdev(tapcp: &Tapcp, args: &Args) -> Result<()> {
+    let mut devices: Vec<_> = tapcp.listdev()?.into_iter().collect();
+    devices.sort_by_key(|(name, (addr, _))| (*addr, name.clone()));
+    if args.flag("json") {
+        let entries: Vec<_> = devices
+            .iter()
+            .map(|(name, (addr, length))| {
+                format!(
+                    "{{\"name\":{},\"address\":{},\"length\":{}}}",
+                    json_string(name),
+                    addr,
+                    length
+                )
+            })
+            .collect();
+        println!("[{}]", entries.join(","));
+        return Ok(());
+    }
+    let width = devices
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!("{:width$}  {:>10}  {:>10}", "NAME", "ADDRESS", "LENGTH");
+    for (name, (addr, length)) in devices {
+        println!("{:width$}  {:#010x}  {:#10x}", name, addr, length);
+    }
+    Ok(())
+}
+
+fn read(tapcp: &Tapcp, args: &Args) -> Result<()> {
+    args.at_most(4)?;
+    let device = args.positional(1, "DEVICE")?;
+    let offset = args.positional.get(2).map_or(Ok(0), |o| parse_number(o))?;
+    let n = args.positional.get(3).map_or(Ok(1), |n| parse_number(n))?;
+    let bytes = tapcp.read_device(device, offset as usize, n as usize)?;
+    output(&bytes, args)
+}
+
+fn write(tapcp: &Tapcp, args: &Args) -> Result<()> {
+    let device = args.positional(1, "DEVICE")?;
+    let offset = parse_number(args.positional(2, "OFFSET")?)?;
+    let data = match args.option("file") {
+        Some(path) => {
+            args.at_most(3)?;
+            fs::read(path)?
+        }
+        None => {
+            let words = &args.positional[3..];
+            if words.is_empty() {
+                return Err(format!("Nothing to write\n\n{}", USAGE).into());
+            }
+            words
+                .iter()
+                .map(|word| Ok(parse_word(word)?.to_be_bytes()))
+                .collect::<Result<Vec<_>>>()?
+                .concat()
+        }
+    };
+    Ok(tapcp.write_device(device, offset as usize, &data)?)
+}
+
+fn flash(tapcp: &Tapcp, args: &Args) -> Result<()> {
+    match args.positional(1, "flash command")? {
+        "read" => {
+            args.at_most(4)?;
+            let offset = parse_number(args.positional(2, "OFFSET")?)?;
+            let n = parse_number(args.positional(3, "N")?)?;
+            let bytes = tapcp.read_flash(offset as usize, n as usize)?;
+            output(&bytes, args)
+        }
+        command => Err(format!("Unknown flash command {}\n\n{}", command, USAGE).into()),
+    }
+}
+
+fn program(tapcp: &Tapcp, args: &Args) -> Result<()> {
+    args.at_most(2)?;
+    let path = args.positional(1, "FILE")?;
+    let bytes = fs::read(path)?;
+    let progress = |progress| match progress {
+        Progress::Writing { sector, sectors } => {
+            eprint!("\rWriting sector {}/{}", sector + 1, sectors)
+        }
+        Progress::Verifying { sector, sectors } => {
+            eprint!("\rVerifying sector {}/{}", sector + 1, sectors)
+        }
+        Progress::Metadata => eprint!("\nWriting metadata"),
+        Progress::Rebooting => eprintln!("\nRebooting"),
+    };
+    if Path::new(path).extension().is_some_and(|ext| ext == "fpg") {
+        tapcp.program_fpg(&Fpg::parse(&bytes)?, progress)?;
+    } else {
+        tapcp.program(&bytes, progress)?;
+    }
+    Ok(())
+}
+
+fn run(args: Args) -> Result<()> {
+    let command = args.positional(0, "COMMAND")?;
+    let tapcp = connect(&args)?;
+    match command {
+        "temp" => {
+            args.at_most(1)?;
+            println!("{}", tapcp.temp()?)
+        }
+        "help" => {
+            args.at_most(1)?;
+            print!("{}", tapcp.help()?)
+        }
+        "listdev" => {
+            args.at_most(1)?;
+            listdev(&tapcp, &args)?
+        }
+        "read" => read(&tapcp, &args)?,
+        "write" => write(&tapcp, &args)?,
+        "flash" => flash(&tapcp, &args)?,
+        "program" => program(&tapcp, &args)?,
+        command => return Err(format!("Unknown command {}\n\n{}", command, USAGE).into()),
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = Args::parse(std::env::args().skip(1)).and_then(run);
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Args {
+        Args::parse(line.split_whitespace().map(str::to_owned)).unwrap()
+    }
+
+    #[test]
+    fn test_args() {
+        let args = args("--host 192.168.0.3 read --format=dec adc 0x10 --json 4");
+        assert_eq!(args.positional, ["read", "adc", "0x10", "4"]);
+        assert_eq!(args.option("host"), Some("192.168.0.3"));
+        assert_eq!(args.option("format"), Some("dec"));
+        assert!(args.flag("json"));
+        assert!(Args::parse(["--timeout".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_unrecognised() {
+        let err = Args::parse(["--verfy".to_owned()]).unwrap_err();
+        assert!(err.to_string().starts_with("Unknown option --verfy"));
+        assert!(Args::parse(["--hots=board".to_owned()]).is_err());
+        let args = args("program design.fpg extra");
+        assert!(args.at_most(2).is_err());
+        assert!(args.at_most(3).is_ok());
+    }
+
+    #[test]
+    fn test_zero_timeout() {
+        let err = connect(&args("--host 127.0.0.1 --timeout 0 temp")).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("--timeout must be at least 1 ms"));
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(parse_number("0x1F").unwrap(), 31);
+        assert_eq!(parse_number("31").unwrap(), 31);
+        assert!(parse_number("thirty").is_err());
+        assert!(parse_word("0x100000000").is_err());
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("adc"), "\"adc\"");
+        assert_eq!(json_string("a\"b\\\n"), "\"a\\\"b\\\\\\u000a\"");
+    }
+}
@@ -0,0 +1,341 @@
+//! Reading and writing large BRAMs a chunk at a time.
+//!
+//! A single TFTP transfer of a multi-megabyte BRAM takes thousands of round trips, and losing any
+//! one of them past the retry limit throws the whole thing away. [`read_bram`] and [`write_bram`]
+//! instead split the memory into chunks of [`Chunking::words`] words, each its own transfer using
+//! the `/dev/NAME.WORD_OFFSET.NWORDS` filename, and retry a failed chunk on its own.
+//!
+//! [`read_samples`] and [`write_samples`] go on to convert the bytes to and from big-endian
+//! [`Sample`] types, like `i16` or [`Complex`] pairs of them.
+//!
+//! FIFOs hand out their next word on every read, so [`read_fifo`] pops them a chunk at a time
+//! without retries, and [`drain_fifo`] keeps going until a fill level register says it's empty.
+
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::{tftp::Config, Error};
+
+/// How to split up a BRAM transfer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Chunking {
+    /// The most 4 byte words to move in a single transfer
+    pub words: usize,
+    /// How many times to retry a chunk that failed before giving up on the whole transfer
+    pub retries: usize,
+}
+
+impl Default for Chunking {
+    /// 32 KiB, or 64 TFTP blocks, per chunk with a couple of retries each
+    fn default() -> Self {
+        Chunking {
+            words: 8192,
+            retries: 2,
+        }
+    }
+}
+
+/// A value stored big-endian in a BRAM
+pub trait Sample: Sized {
+    /// Size in bytes
+    const SIZE: usize;
+    /// Decode from exactly `SIZE` bytes
+    fn from_be_bytes(bytes: &[u8]) -> Self;
+    fn extend_be_bytes(&self, bytes: &mut Vec<u8>);
+}
+
+macro_rules! impl_sample {
+    ($($t:ty),*) => {
+        $(
+            impl Sample for $t {
+                const SIZE: usize = std::mem::size_of::<$t>();
+
+                fn from_be_bytes(bytes: &[u8]) -> Self {
+                    <$t>::from_be_bytes(bytes.try_into().expect("Sliced to SIZE bytes"))
+                }
+
+                fn extend_be_bytes(&self, bytes: &mut Vec<u8>) {
+                    bytes.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_sample!(u8, i8, u16, i16, u32, i32);
+
+/// A complex sample, stored as the real part followed by the imaginary part
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Complex<T> {
+    pub re: T,
+    pub im: T,
+}
+
+impl<T: Sample> Sample for Complex<T> {
+    const SIZE: usize = 2 * T::SIZE;
+
+    fn from_be_bytes(bytes: &[u8]) -> Self {
+        Complex {
+            re: T::from_be_bytes(&bytes[..T::SIZE]),
+            im: T::from_be_bytes(&bytes[T::SIZE..]),
+        }
+    }
+
+    fn extend_be_bytes(&self, bytes: &mut Vec<u8>) {
+        self.re.extend_be_bytes(bytes);
+        self.im.extend_be_bytes(bytes);
+    }
+}
+
+/// Decode `bytes` as a run of `T`, ignoring any partial sample at the end
+pub fn decode<T: Sample>(bytes: &[u8]) -> Vec<T> {
+    bytes.chunks_exact(T::SIZE).map(T::from_be_bytes).collect()
+}
+
+/// Encode `samples` as big-endian bytes
+pub fn encode<T: Sample>(samples: &[T]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * T::SIZE);
+    for sample in samples {
+        sample.extend_be_bytes(&mut bytes);
+    }
+    bytes
+}
+
+/// Split `n` words starting at word `offset` into (offset,n) chunks of at most `words` words
+fn chunks(offset: usize, n: usize, words: usize) -> impl Iterator<Item = (usize, usize)> {
+    let words = words.max(1);
+    (offset..offset + n)
+        .step_by(words)
+        .map(move |start| (start, words.min(offset + n - start)))
+}
+
+/// Run `f` until it succeeds or fails more than `retries` times. Only errors from the transfer
+/// itself are worth retrying, anything else (like a device that doesn't exist) will just fail
+/// again.
+fn retry<T, F>(retries: usize, mut f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut attempts = 0;
+    loop {
+        match f() {
+            Err(Error::Tftp(_) | Error::Io(_)) if attempts < retries => attempts += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Read `n` words at word `offset` into `device` in chunks
+pub fn read_bram(
+    device: &str,
+    offset: usize,
+    n: usize,
+    chunking: Chunking,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::with_capacity(n * 4);
+    for (offset, n) in chunks(offset, n, chunking.words) {
+        let chunk = retry(chunking.retries, || {
+            crate::read_device(device, offset, n, socket, addr, config)
+        })?;
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+/// Write `data`, which must be a whole number of words, at word `offset` into `device` in chunks
+pub fn write_bram(
+    device: &str,
+    offset: usize,
+    data: &[u8],
+    chunking: Chunking,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    if data.len() % 4 != 0 {
+        return Err(Error::Length {
+            expected: (data.len() + 3) / 4 * 4,
+            received: data.len(),
+        });
+    }
+    for (word, n) in chunks(offset, data.len() / 4, chunking.words) {
+        let start = (word - offset) * 4;
+        let chunk = &data[start..start + n * 4];
+        retry(chunking.retries, || {
+            crate::write_device(device, word, chunk, socket, addr, config)
+        })?;
+    }
+    Ok(())
+}
+
+/// Read `count` samples of `T` starting at word `offset` into `device` in chunks
+pub fn read_samples<T: Sample>(
+    device: &str,
+    offset: usize,
+    count: usize,
+    chunking: Chunking,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<T>, Error> {
+    let words = (count * T::SIZE + 3) / 4;
+    let bytes = read_bram(device, offset, words, chunking, socket, addr, config)?;
+    let mut samples = decode(&bytes);
+    samples.truncate(count);
+    Ok(samples)
+}
+
+/// Write `samples` starting at word `offset` into `device` in chunks. They must add up to a whole
+/// number of words.
+pub fn write_samples<T: Sample>(
+    device: &str,
+    offset: usize,
+    samples: &[T],
+    chunking: Chunking,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    write_bram(
+        device,
+        offset,
+        &encode(samples),
+        chunking,
+        socket,
+        addr,
+        config,
+    )
+}
+
+/// Pop `n` words off the FIFO `device`, which hands out its next word on every read whatever the
+/// offset. Failed chunks aren't retried, as whatever they read has already left the FIFO.
+pub fn read_fifo(
+    device: &str,
+    n: usize,
+    chunking: Chunking,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::with_capacity(n * 4);
+    for (_, n) in chunks(0, n, chunking.words) {
+        bytes.extend_from_slice(&crate::read_device(device, 0, n, socket, addr, config)?);
+    }
+    Ok(bytes)
+}
+
+/// Pop words off the FIFO `device` until the register `level`, which holds how many words are
+/// waiting in it, reads 0
+pub fn drain_fifo(
+    device: &str,
+    level: &str,
+    chunking: Chunking,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![];
+    loop {
+        let waiting: u32 = crate::read_register(level, 0, socket, addr, config)?;
+        if waiting == 0 {
+            return Ok(bytes);
+        }
+        let popped = read_fifo(device, waiting as usize, chunking, socket, addr, config)?;
+        bytes.extend_from_slice(&popped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{Board, Simulator},
+        tftp::{tests::next_packet, Payload},
+    };
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(
+            chunks(4, 10, 4).collect::<Vec<_>>(),
+            vec![(4, 4), (8, 4), (12, 2)]
+        );
+        assert_eq!(chunks(0, 0, 4).count(), 0);
+    }
+
+    #[test]
+    fn test_samples() {
+        let bytes = [0x00, 0x01, 0xFF, 0xFE, 0x12, 0x34];
+        assert_eq!(decode::<i16>(&bytes), vec![1, -2, 0x1234]);
+        assert_eq!(decode::<u32>(&bytes), vec![0x0001FFFE]);
+        let complex = decode::<Complex<i16>>(&bytes);
+        assert_eq!(complex, vec![Complex { re: 1, im: -2 }]);
+        assert_eq!(encode(&complex), bytes[..4]);
+        assert_eq!(encode(&[0x1234u16, 0x5678]), [0x12, 0x34, 0x56, 0x78]);
+    }
+
+    #[test]
+    fn test_retry_chunk() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut filenames = vec![];
+            for respond in [false, true, true] {
+                let (request, from) = next_packet(&server);
+                if let Payload::Read { filename, .. } = request {
+                    filenames.push(filename);
+                }
+                if respond {
+                    let data = Payload::Data {
+                        block: 1,
+                        data: vec![0xAB; 8],
+                    };
+                    server.send_to(&data.pack(), from).unwrap();
+                    next_packet(&server);
+                }
+            }
+            filenames
+        });
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            timeout: Duration::from_millis(50),
+            retries: 0,
+            ..Default::default()
+        };
+        let chunking = Chunking {
+            words: 2,
+            retries: 1,
+        };
+        let samples: Vec<u16> =
+            read_samples("bram", 0, 8, chunking, &mut socket, addr, &config).unwrap();
+        assert_eq!(samples, vec![0xABAB; 8]);
+        // The first chunk was lost and asked for again
+        assert_eq!(
+            handle.join().unwrap(),
+            ["/dev/bram.0.2", "/dev/bram.0.2", "/dev/bram.2.2"]
+        );
+    }
+
+    #[test]
+    fn test_fifo() {
+        let sim = Simulator::start(Board::new().with_fifo("fifo", 0x1000)).unwrap();
+        let words: Vec<u8> = (0..3000u32).flat_map(u32::to_be_bytes).collect();
+        sim.board().push_fifo("fifo", &words);
+        let tapcp = sim.tapcp().unwrap();
+        let chunking = Chunking {
+            words: 256,
+            retries: 0,
+        };
+        let popped = tapcp.read_fifo("fifo", 1000, chunking).unwrap();
+        assert_eq!(popped, words[..4000]);
+        assert_eq!(tapcp.read_register::<u32>("fifo_level", 0).unwrap(), 2000);
+        let drained = tapcp.drain_fifo("fifo", "fifo_level", chunking).unwrap();
+        assert_eq!(drained, words[4000..]);
+        assert!(tapcp
+            .drain_fifo("fifo", "fifo_level", chunking)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,609 @@
+//! A handle on a single board that owns its socket and settings
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use crate::{
+    bram::{Chunking, Sample},
+    flash::{Probe, Progress},
+    fpg::Fpg,
+    protocol,
+    register::{Bitfields, Fixed, Word},
+    snapshot::{Arm, Capture, Snapshot},
+    tftp::{Config, Rollover},
+    Error,
+};
+
+/// The well-known TFTP port the board listens for requests on
+const TFTP_PORT: u16 = 69;
+
+/// Builds a [`Tapcp`], see [`Tapcp::builder`]
+#[derive(Debug, Clone)]
+pub struct TapcpBuilder {
+    host: String,
+    port: u16,
+    bind: Option<SocketAddr>,
+    config: Config,
+}
+
+impl TapcpBuilder {
+    /// The port the board's TFTP server listens on, 69 by default
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// The local address to bind our socket to.
+    /// By default we let the OS pick an ephemeral port on all interfaces.
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.bind = Some(addr);
+        self
+    }
+
+    /// How long we wait for a response to any single packet before resending it. This must be
+    /// nonzero, or [`Self::build`] fails.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// How many times we resend a packet before giving up on the transfer
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.config.retries = retries;
+        self
+    }
+
+    /// Where block numbers wrap to for transfers of more than 65535 blocks
+    pub fn rollover(mut self, rollover: Rollover) -> Self {
+        self.config.rollover = rollover;
+        self
+    }
+
+    /// Resolve the board's address and bind our socket
+    pub fn build(self) -> Result<Tapcp, Error> {
+        if self.config.timeout.is_zero() {
+            return Err(Error::ZeroTimeout);
+        }
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or(Error::Resolve(self.host))?;
+        let bind = self.bind.unwrap_or_else(|| match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        });
+        let socket = UdpSocket::bind(bind)?;
+        Ok(Tapcp {
+            inner: Arc::new(Inner {
+                socket: Mutex::new(socket),
+                addr,
+                config: self.config,
+                devices: Mutex::new(None),
+            }),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// The board only handles one transfer at a time anyway, so everyone takes turns on one socket
+    socket: Mutex<UdpSocket>,
+    addr: SocketAddr,
+    config: Config,
+    /// The `listdev` map, once we've asked for it
+    devices: Mutex<Option<HashMap<String, (u32, u32)>>>,
+}
+
+/// A connection to a single TAPCP board.
+///
+/// Cloning is cheap and clones share the same socket and device list, so a `Tapcp` can be handed
+/// out to as many threads as need it. Operations from different clones are run one at a time.
+#[derive(Debug, Clone)]
+pub struct Tapcp {
+    inner: Arc<Inner>,
+}
+
+impl Tapcp {
+    /// Start building a connection to the board at `host`
+    pub fn builder(host: &str) -> TapcpBuilder {
+        TapcpBuilder {
+            host: host.to_owned(),
+            port: TFTP_PORT,
+            bind: None,
+            config: Config::default(),
+        }
+    }
+
+    /// Connect to the board at `host` with all the default settings
+    pub fn connect(host: &str) -> Result<Self, Error> {
+        Self::builder(host).build()
+    }
+
+    /// The address of the board's TFTP server
+    pub fn addr(&self) -> SocketAddr {
+        self.inner.addr
+    }
+
+    /// The TFTP settings every transfer uses
+    pub fn config(&self) -> &Config {
+        &self.inner.config
+    }
+
+    fn socket(&self) -> MutexGuard<'_, UdpSocket> {
+        // A panic mid-transfer doesn't leave the socket in any state we care about
+        self.inner
+            .socket
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Gets the temperature of the remote device in Celsius
+    pub fn temp(&self) -> Result<f32, Error> {
+        crate::temp(&mut self.socket(), self.addr(), self.config())
+    }
+
+    /// Gets the list of top level commands (as a string)
+    pub fn help(&self) -> Result<String, Error> {
+        crate::help(&mut self.socket(), self.addr(), self.config())
+    }
+
+    /// Gets the list of all devices supported by the currently running gateware as a hash map from
+    /// device name to (addr,length). We only ask the board the first time, use
+    /// [`Self::refresh_listdev`] if the gateware has changed since.
+    pub fn listdev(&self) -> Result<HashMap<String, (u32, u32)>, Error> {
+        if let Some(devices) = self.devices().as_ref() {
+            return Ok(devices.clone());
+        }
+        self.refresh_listdev()
+    }
+
+    /// Ask the board for its list of devices again, replacing the cached one
+    pub fn refresh_listdev(&self) -> Result<HashMap<String, (u32, u32)>, Error> {
+        let devices = crate::listdev(&mut self.socket(), self.addr(), self.config())?;
+        *self.devices() = Some(devices.clone());
+        Ok(devices)
+    }
+
+    fn devices(&self) -> MutexGuard<'_, Option<HashMap<String, (u32, u32)>>> {
+        self.inner
+            .devices
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Look up the (addr,length) of a single device, from the cached device list if we have one
+    pub fn find_device(&self, device: &str) -> Result<Option<(u32, u32)>, Error> {
+        if let Some(devices) = self.devices().as_ref() {
+            return Ok(devices.get(device).copied());
+        }
+        crate::find_device(device, &mut self.socket(), self.addr(), self.config())
+    }
+
+    /// Find which device's memory contains the bus address `address`, from the cached device list
+    /// if we have one. Returns the name of the device and how many bytes into it `address` is.
+    pub fn device_at(&self, address: u32) -> Result<Option<(String, u32)>, Error> {
+        if let Some(devices) = self.devices().as_ref() {
+            return Ok(devices
+                .iter()
+                .find(|(_, &device)| protocol::contains(address, device))
+                .map(|(device, (addr, _))| (device.clone(), address - addr)));
+        }
+        crate::device_at(address, &mut self.socket(), self.addr(), self.config())
+    }
+
+    /// Read memory associated with the gateware device `device`, see [`crate::read_device`]
+    pub fn read_device(&self, device: &str, offset: usize, n: usize) -> Result<Vec<u8>, Error> {
+        crate::read_device(
+            device,
+            offset,
+            n,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Write bytes to the device named `device`, see [`crate::write_device`]
+    pub fn write_device(&self, device: &str, offset: usize, data: &[u8]) -> Result<(), Error> {
+        crate::write_device(
+            device,
+            offset,
+            data,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Read a software register `byte_offset` bytes into `device` as a `T`, see
+    /// [`crate::read_register`]
+    pub fn read_register<T: Word>(&self, device: &str, byte_offset: usize) -> Result<T, Error> {
+        crate::read_register(
+            device,
+            byte_offset,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Write a `T` to a software register `byte_offset` bytes into `device`, see
+    /// [`crate::write_register`]
+    pub fn write_register<T: Word>(
+        &self,
+        device: &str,
+        byte_offset: usize,
+        value: T,
+    ) -> Result<(), Error> {
+        crate::write_register(
+            device,
+            byte_offset,
+            value,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Read a software register `byte_offset` bytes into `device` as a fixed-point number, see
+    /// [`crate::read_fixed`]
+    pub fn read_fixed(
+        &self,
+        device: &str,
+        byte_offset: usize,
+        format: Fixed,
+    ) -> Result<f64, Error> {
+        crate::read_fixed(
+            device,
+            byte_offset,
+            format,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Write a fixed-point number to a software register `byte_offset` bytes into `device`, see
+    /// [`crate::write_fixed`]
+    pub fn write_fixed(
+        &self,
+        device: &str,
+        byte_offset: usize,
+        value: f64,
+        format: Fixed,
+    ) -> Result<(), Error> {
+        crate::write_fixed(
+            device,
+            byte_offset,
+            value,
+            format,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Read a large BRAM in chunks, see [`crate::bram::read_bram`]
+    pub fn read_bram(
+        &self,
+        device: &str,
+        offset: usize,
+        n: usize,
+        chunking: Chunking,
+    ) -> Result<Vec<u8>, Error> {
+        crate::bram::read_bram(
+            device,
+            offset,
+            n,
+            chunking,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Write a large BRAM in chunks, see [`crate::bram::write_bram`]
+    pub fn write_bram(
+        &self,
+        device: &str,
+        offset: usize,
+        data: &[u8],
+        chunking: Chunking,
+    ) -> Result<(), Error> {
+        crate::bram::write_bram(
+            device,
+            offset,
+            data,
+            chunking,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Read typed samples out of a BRAM in chunks, see [`crate::bram::read_samples`]
+    pub fn read_samples<T: Sample>(
+        &self,
+        device: &str,
+        offset: usize,
+        count: usize,
+        chunking: Chunking,
+    ) -> Result<Vec<T>, Error> {
+        crate::bram::read_samples(
+            device,
+            offset,
+            count,
+            chunking,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Write typed samples into a BRAM in chunks, see [`crate::bram::write_samples`]
+    pub fn write_samples<T: Sample>(
+        &self,
+        device: &str,
+        offset: usize,
+        samples: &[T],
+        chunking: Chunking,
+    ) -> Result<(), Error> {
+        crate::bram::write_samples(
+            device,
+            offset,
+            samples,
+            chunking,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Pop words off a FIFO in chunks, see [`crate::bram::read_fifo`]
+    pub fn read_fifo(&self, device: &str, n: usize, chunking: Chunking) -> Result<Vec<u8>, Error> {
+        crate::bram::read_fifo(
+            device,
+            n,
+            chunking,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Pop everything off a FIFO, see [`crate::bram::drain_fifo`]
+    pub fn drain_fifo(
+        &self,
+        device: &str,
+        level: &str,
+        chunking: Chunking,
+    ) -> Result<Vec<u8>, Error> {
+        crate::bram::drain_fifo(
+            device,
+            level,
+            chunking,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Read one field of a register `byte_offset` bytes into `device`, see [`crate::read_field`]
+    pub fn read_field(
+        &self,
+        device: &str,
+        byte_offset: usize,
+        layout: &Bitfields,
+        name: &str,
+    ) -> Result<i64, Error> {
+        crate::read_field(
+            device,
+            byte_offset,
+            layout,
+            name,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Set one field of a register `byte_offset` bytes into `device`, leaving the rest alone, see
+    /// [`crate::write_field`]
+    pub fn write_field(
+        &self,
+        device: &str,
+        byte_offset: usize,
+        layout: &Bitfields,
+        name: &str,
+        value: i64,
+    ) -> Result<(), Error> {
+        crate::write_field(
+            device,
+            byte_offset,
+            layout,
+            name,
+            value,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Find the snapshot block `name`, from the cached device list if we have one
+    pub fn snapshot(&self, name: &str) -> Result<Snapshot, Error> {
+        Snapshot::find(name, &self.listdev()?)
+    }
+
+    /// Arm `snapshot`, wait up to `timeout` for it to fill and read it back, see
+    /// [`Snapshot::capture`]
+    pub fn capture(
+        &self,
+        snapshot: &Snapshot,
+        options: Arm,
+        timeout: Duration,
+    ) -> Result<Capture, Error> {
+        snapshot.capture(
+            options,
+            timeout,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Read memory from the onboard flash, see [`crate::read_flash`]
+    pub fn read_flash(&self, offset: usize, n: usize) -> Result<Vec<u8>, Error> {
+        crate::read_flash(offset, n, &mut self.socket(), self.addr(), self.config())
+    }
+
+    /// Write bytes to the onboard flash, see [`crate::write_flash`]
+    pub fn write_flash(&self, offset: usize, data: &[u8], verify: bool) -> Result<(), Error> {
+        crate::write_flash(
+            offset,
+            data,
+            verify,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Program the board with a raw bitstream, see [`crate::program`]. This forgets the cached
+    /// device list, as the new gateware will have its own.
+    pub fn program<F>(&self, bitstream: &[u8], progress: F) -> Result<(), Error>
+    where
+        F: FnMut(Progress),
+    {
+        *self.devices() = None;
+        crate::program(
+            bitstream,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+            progress,
+        )
+    }
+
+    /// Program the board from an `.fpg`, keeping its header in flash, see [`crate::program_fpg`].
+    /// This forgets the cached device list, as the new gateware will have its own.
+    pub fn program_fpg<F>(&self, fpg: &Fpg, progress: F) -> Result<(), Error>
+    where
+        F: FnMut(Progress),
+    {
+        *self.devices() = None;
+        crate::program_fpg(
+            fpg,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+            progress,
+        )
+    }
+
+    /// Reboot the board into the image at byte address `address` in flash, see [`crate::progdev`].
+    /// This forgets the cached device list, as the new gateware will have its own.
+    pub fn progdev(&self, address: u32) -> Result<(), Error> {
+        *self.devices() = None;
+        crate::progdev(address, &mut self.socket(), self.addr(), self.config())
+    }
+
+    /// Reboot the board into the golden image, see [`crate::reboot_golden`]
+    pub fn reboot_golden(&self) -> Result<(), Error> {
+        *self.devices() = None;
+        crate::reboot_golden(&mut self.socket(), self.addr(), self.config())
+    }
+
+    /// Wait for the board to answer again after a reboot, see [`crate::wait_until_responsive`]
+    pub fn wait_until_responsive(
+        &self,
+        timeout: Duration,
+        probe: Probe,
+    ) -> Result<Duration, Error> {
+        crate::wait_until_responsive(
+            timeout,
+            probe,
+            &mut self.socket(),
+            self.addr(),
+            self.config(),
+        )
+    }
+
+    /// Read back the header of the design in flash, see [`crate::read_fpg`]
+    pub fn read_fpg(&self) -> Result<Option<Fpg>, Error> {
+        crate::read_fpg(&mut self.socket(), self.addr(), self.config())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tftp::{tests::next_packet, Payload};
+    use std::thread;
+
+    /// A client pointed at a scripted loopback server
+    fn loopback() -> (Tapcp, UdpSocket) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let tapcp = Tapcp::builder("127.0.0.1")
+            .port(server.local_addr().unwrap().port())
+            .timeout(Duration::from_millis(50))
+            .retries(1)
+            .build()
+            .unwrap();
+        (tapcp, server)
+    }
+
+    #[test]
+    fn test_shareable() {
+        fn assert_send_sync<T: Send + Sync + Clone>() {}
+        assert_send_sync::<Tapcp>();
+    }
+
+    #[test]
+    fn test_zero_timeout() {
+        let result = Tapcp::builder("127.0.0.1").timeout(Duration::ZERO).build();
+        assert!(matches!(result, Err(Error::ZeroTimeout)));
+    }
+
+    #[test]
+    fn test_temp() {
+        let (tapcp, server) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            let data = Payload::Data {
+                block: 1,
+                data: 42.5f32.to_be_bytes().to_vec(),
+            };
+            server.send_to(&data.pack(), from).unwrap();
+            next_packet(&server);
+        });
+        // Run it from another thread, through a clone
+        let clone = tapcp.clone();
+        let temp = thread::spawn(move || clone.temp()).join().unwrap();
+        assert_eq!(temp.unwrap(), 42.5);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_listdev_cached() {
+        let (tapcp, server) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            // An empty device list
+            let data = Payload::Data {
+                block: 1,
+                data: vec![0, 0, 8, 0, 0],
+            };
+            server.send_to(&data.pack(), from).unwrap();
+            next_packet(&server);
+        });
+        assert!(tapcp.listdev().unwrap().is_empty());
+        handle.join().unwrap();
+        // The server is gone, so this only works if we don't ask again
+        assert!(tapcp.listdev().unwrap().is_empty());
+        assert!(tapcp.refresh_listdev().is_err());
+    }
+}
@@ -1,7 +1,393 @@
-#![allow(non_upper_case_globals)]
-#![allow(non_camel_case_types)]
-#![allow(non_snake_case)]
-#![allow(clippy::missing_safety_doc)]
-#![allow(dead_code)]
+//! Compact Sorted Lists (CSL), the format TAPCP uses for the `/listdev` device list.
+//!
+//! A CSL stores a sorted list of strings compactly by reusing as many leading characters as
+//! possible from one entry to the next. Each entry is followed by a fixed size payload, making it
+//! look like a limited form of sorted hash table.
+//!
+//! The first byte of a CSL is the payload size. Then, each entry is a head size (the number of
+//! leading characters shared with the previous entry), a tail size (the number of characters that
+//! differ), the tail characters themselves (with no NUL) and finally the payload. The first entry
+//! has no previous entry, so it has no head size byte at all. The list ends with an entry whose
+//! head and tail sizes are both zero. All sizes are single bytes, so keys and payloads are at most
+//! 255 bytes.
+//!
+//! For example, this list of keys with one byte payloads
+//!
+//! ```text
+//! adc16_wb_ram1   1
+//! adc16_wb_ram2   2
+//! eq_0_gain       3
+//! eq_1_gain       4
+//! eth_0_bframes   5
+//! eth_0_core      6
+//! ```
+//!
+//! is stored as (with line breaks for clarity)
+//!
+//! ```text
+//! 01 0D a d c 1 6 _ w b _ r a m 1 01
+//! 0C 01 2 02
+//! 00 09 e q _ 0 _ g a i n 03
+//! 03 06 1 _ g a i n 04
+//! 01 0C t h _ 0 _ b f r a m e s 05
+//! 06 04 c o r e 06
+//! 00 00
+//! ```
+//!
+//! [`iter`] decodes a CSL and [`encode`] builds one. [`find_key`] and [`find_by_payload`] search
+//! one without decoding the whole thing up front.
 
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+/// Errors from decoding a malformed CSL, or from trying to encode one we can't represent
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("The CSL is missing its payload size")]
+    Empty,
+    #[error("The CSL ended in the middle of an entry")]
+    Truncated,
+    #[error("An entry shares {head} characters with a previous key that only has {previous}")]
+    BadHead { head: usize, previous: usize },
+    #[error("A key wasn't valid UTF-8")]
+    Utf8,
+    #[error("Keys can't be empty")]
+    EmptyKey,
+    #[error("The key {0} is longer than 255 bytes")]
+    KeyTooLong(String),
+    #[error("The key {0} isn't strictly after the one before it")]
+    Unsorted(String),
+    #[error("Payloads must be {expected} bytes, but the one for {key} is {received}")]
+    PayloadSize {
+        key: String,
+        expected: usize,
+        received: usize,
+    },
+}
+
+/// Encode `entries`, which must be sorted by key with no duplicates, into a CSL where every
+/// payload is `payload_size` bytes
+pub fn encode<K, P>(
+    payload_size: u8,
+    entries: impl IntoIterator<Item = (K, P)>,
+) -> Result<Vec<u8>, Error>
+where
+    K: AsRef<str>,
+    P: AsRef<[u8]>,
+{
+    let mut bytes = vec![payload_size];
+    let mut previous: Option<String> = None;
+    for (key, payload) in entries {
+        let key = key.as_ref();
+        let payload = payload.as_ref();
+        if key.is_empty() {
+            return Err(Error::EmptyKey);
+        }
+        if key.len() > u8::MAX as usize {
+            return Err(Error::KeyTooLong(key.to_owned()));
+        }
+        if payload.len() != payload_size as usize {
+            return Err(Error::PayloadSize {
+                key: key.to_owned(),
+                expected: payload_size as usize,
+                received: payload.len(),
+            });
+        }
+        let head = match &previous {
+            // Sorting also rules out a zero tail size, which would look like the end of the list
+            Some(previous) if key <= previous.as_str() => {
+                return Err(Error::Unsorted(key.to_owned()))
+            }
+            Some(previous) => {
+                let head = previous
+                    .bytes()
+                    .zip(key.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                // The first entry's head size is the payload size instead
+                bytes.push(head as u8);
+                head
+            }
+            None => 0,
+        };
+        let tail = &key.as_bytes()[head..];
+        bytes.push(tail.len() as u8);
+        bytes.extend_from_slice(tail);
+        bytes.extend_from_slice(payload);
+        previous = Some(key.to_owned());
+    }
+    // Zero head and tail sizes mark the end
+    bytes.extend_from_slice(&[0, 0]);
+    Ok(bytes)
+}
+
+/// Iterate over the `(key, payload)` entries of the CSL in `bytes`
+pub fn iter(bytes: &[u8]) -> Result<Iter<'_>, Error> {
+    let (&payload_size, bytes) = bytes.split_first().ok_or(Error::Empty)?;
+    Ok(Iter {
+        bytes,
+        payload_size: payload_size as usize,
+        key: vec![],
+        first: true,
+        done: false,
+    })
+}
+
+/// An iterator over the entries in a CSL, see [`iter`]
+#[derive(Debug, Clone)]
+pub struct Iter<'a> {
+    /// What's left of the CSL
+    bytes: &'a [u8],
+    payload_size: usize,
+    /// The key of the previous entry
+    key: Vec<u8>,
+    /// The first entry doesn't have a head size
+    first: bool,
+    /// Set at the end of the list, or after an error
+    done: bool,
+}
+
+impl<'a> Iter<'a> {
+    /// The size of every entry's payload
+    pub fn payload_size(&self) -> usize {
+        self.payload_size
+    }
+
+    /// Take `n` bytes off the front of what's left
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < n {
+            return Err(Error::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(String, &'a [u8])>, Error> {
+        let head = if self.first {
+            self.first = false;
+            0
+        } else {
+            self.take(1)?[0] as usize
+        };
+        let tail = self.take(1)?[0] as usize;
+        if tail == 0 {
+            return Ok(None);
+        }
+        if head > self.key.len() {
+            return Err(Error::BadHead {
+                head,
+                previous: self.key.len(),
+            });
+        }
+        self.key.truncate(head);
+        let tail = self.take(tail)?;
+        self.key.extend_from_slice(tail);
+        let payload = self.take(self.payload_size)?;
+        let key = std::str::from_utf8(&self.key).map_err(|_| Error::Utf8)?;
+        Ok(Some((key.to_owned(), payload)))
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(String, &'a [u8]), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.next_entry().transpose();
+        // Stop at the end of the list, and don't try to keep going after garbage
+        if !matches!(entry, Some(Ok(_))) {
+            self.done = true;
+        }
+        entry
+    }
+}
+
+/// Look up the payload for `key` in the CSL in `bytes`, if it's there. As the keys are sorted, we
+/// can stop as soon as we've passed where it would be.
+pub fn find_key<'a>(bytes: &'a [u8], key: &str) -> Result<Option<&'a [u8]>, Error> {
+    for entry in iter(bytes)? {
+        let (candidate, payload) = entry?;
+        match candidate.as_str().cmp(key) {
+            std::cmp::Ordering::Less => (),
+            std::cmp::Ordering::Equal => return Ok(Some(payload)),
+            std::cmp::Ordering::Greater => break,
+        }
+    }
+    Ok(None)
+}
+
+/// Iterate over the entries in the CSL in `bytes` whose payloads satisfy `predicate`
+pub fn find_by_payload<F>(
+    bytes: &[u8],
+    mut predicate: F,
+) -> Result<impl Iterator<Item = Result<(String, &[u8]), Error>>, Error>
+where
+    F: FnMut(&[u8]) -> bool,
+{
+    Ok(iter(bytes)?.filter(move |entry| match entry {
+        Ok((_, payload)) => predicate(payload),
+        // Don't swallow errors
+        Err(_) => true,
+    }))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The example from the module docs (and Dave's original C header)
+    pub(crate) fn example() -> Vec<u8> {
+        let mut bytes = vec![0x01, 0x0D];
+        bytes.extend_from_slice(b"adc16_wb_ram1\x01");
+        bytes.extend_from_slice(b"\x0C\x012\x02");
+        bytes.extend_from_slice(b"\x00\x09eq_0_gain\x03");
+        bytes.extend_from_slice(b"\x03\x061_gain\x04");
+        bytes.extend_from_slice(b"\x01\x0Cth_0_bframes\x05");
+        bytes.extend_from_slice(b"\x06\x04core\x06");
+        bytes.extend_from_slice(&[0, 0]);
+        bytes
+    }
+
+    #[test]
+    fn test_example() {
+        let entries: Vec<_> = iter(&example())
+            .unwrap()
+            .map(|entry| {
+                let (key, payload) = entry.unwrap();
+                (key, payload[0])
+            })
+            .collect();
+        let expected = [
+            ("adc16_wb_ram1", 1),
+            ("adc16_wb_ram2", 2),
+            ("eq_0_gain", 3),
+            ("eq_1_gain", 4),
+            ("eth_0_bframes", 5),
+            ("eth_0_core", 6),
+        ];
+        assert_eq!(entries.len(), expected.len());
+        for ((key, payload), (expected_key, expected_payload)) in entries.iter().zip(expected) {
+            assert_eq!(key, expected_key);
+            assert_eq!(*payload, expected_payload);
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(iter(&[]).unwrap_err(), Error::Empty);
+        assert_eq!(iter(&[8, 0]).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let example = example();
+        // Chop it off in the middle of the second entry
+        let mut entries = iter(&example[..18]).unwrap();
+        assert!(entries.next().unwrap().is_ok());
+        assert_eq!(entries.next().unwrap().unwrap_err(), Error::Truncated);
+        assert!(entries.next().is_none());
+        // Or with no terminator at all
+        let without_end = &example[..example.len() - 2];
+        let result: Result<Vec<_>, _> = iter(without_end).unwrap().collect();
+        assert_eq!(result.unwrap_err(), Error::Truncated);
+    }
+
+    #[test]
+    fn test_bad_head() {
+        // The second entry claims to share 9 characters with a 3 character key
+        let bytes = [1, 3, b'f', b'o', b'o', 0, 9, 1, b'x', 0, 0, 0];
+        let result: Result<Vec<_>, _> = iter(&bytes).unwrap().collect();
+        assert_eq!(
+            result.unwrap_err(),
+            Error::BadHead {
+                head: 9,
+                previous: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_encode_example() {
+        let entries = [
+            ("adc16_wb_ram1", [1]),
+            ("adc16_wb_ram2", [2]),
+            ("eq_0_gain", [3]),
+            ("eq_1_gain", [4]),
+            ("eth_0_bframes", [5]),
+            ("eth_0_core", [6]),
+        ];
+        assert_eq!(encode(1, entries).unwrap(), example());
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let entries: Vec<(String, Vec<u8>)> = ["a", "ab", "abc", "abd", "b", "sys_board_id"]
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key.to_string(), (0..8).map(|j| (i * 8 + j) as u8).collect()))
+            .collect();
+        let bytes = encode(8, entries.clone()).unwrap();
+        let decoded: Vec<_> = iter(&bytes)
+            .unwrap()
+            .map(|entry| {
+                let (key, payload) = entry.unwrap();
+                (key, payload.to_vec())
+            })
+            .collect();
+        assert_eq!(decoded, entries);
+        // And nothing at all
+        let none: [(&str, [u8; 0]); 0] = [];
+        assert_eq!(iter(&encode(0, none).unwrap()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        assert_eq!(
+            encode(1, [("b", [0]), ("a", [0])]).unwrap_err(),
+            Error::Unsorted("a".to_owned())
+        );
+        assert_eq!(
+            encode(1, [("a", [0]), ("a", [0])]).unwrap_err(),
+            Error::Unsorted("a".to_owned())
+        );
+        assert_eq!(encode(1, [("", [0])]).unwrap_err(), Error::EmptyKey);
+        assert!(matches!(
+            encode(2, [("a", [0])]).unwrap_err(),
+            Error::PayloadSize {
+                expected: 2,
+                received: 1,
+                ..
+            }
+        ));
+        let long = "x".repeat(256);
+        assert_eq!(
+            encode(1, [(long.as_str(), [0])]).unwrap_err(),
+            Error::KeyTooLong(long)
+        );
+    }
+
+    #[test]
+    fn test_find_key() {
+        let example = example();
+        assert_eq!(find_key(&example, "eq_1_gain").unwrap(), Some(&[4u8][..]));
+        assert_eq!(find_key(&example, "eth_0_core").unwrap(), Some(&[6u8][..]));
+        assert_eq!(find_key(&example, "eq").unwrap(), None);
+        assert_eq!(find_key(&example, "zzz").unwrap(), None);
+        // We should give up before hitting the truncation at the end
+        assert_eq!(
+            find_key(&example[..30], "adc16_wb_ram1").unwrap(),
+            Some(&[1u8][..])
+        );
+        assert_eq!(find_key(&example[..30], "zzz"), Err(Error::Truncated));
+    }
+
+    #[test]
+    fn test_find_by_payload() {
+        let example = example();
+        let keys: Vec<_> = find_by_payload(&example, |payload| payload[0] % 2 == 0)
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect();
+        assert_eq!(keys, vec!["adc16_wb_ram2", "eq_1_gain", "eth_0_core"]);
+    }
+}
@@ -0,0 +1,736 @@
+//! The onboard configuration flash, and programming the FPGA from it.
+//!
+//! The layout here matches what casperfpga uses for SNAP-style boards: the golden image lives at
+//! the bottom of flash, and user bitstreams are written starting at [`USER_FLASH_ADDR`]. We refuse
+//! to write anywhere below [`GOLDEN_IMAGE_END`], as a bad golden image can leave the board unable
+//! to boot without a JTAG cable, and we check a bitstream fits below [`FLASH_SIZE`] before
+//! writing any of it.
+//!
+//! Like casperfpga, when we program from an [`Fpg`] we also keep its header in flash, right after
+//! the bitstream, and note where it is in the metadata sector at [`METADATA_ADDR`]. That way the
+//! register layout of whatever is running can be found again after a reboot with [`read_fpg`].
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    ops::Range,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    fpg::Fpg,
+    protocol,
+    tftp::{self, Config, Transfer},
+    Error,
+};
+
+/// Flash is erased a sector at a time, so that's how we write it
+pub const SECTOR_SIZE: usize = 0x10000;
+
+/// The byte address in flash of the golden image, the known-good design the board boots into on
+/// power up
+pub const GOLDEN_IMAGE_ADDR: u32 = 0;
+
+/// The byte address in flash where user bitstreams start
+pub const USER_FLASH_ADDR: u32 = 0x800000;
+
+/// How big the flash is on SNAP-style boards, with the user region taking the top half
+pub const FLASH_SIZE: usize = 0x1000000;
+
+/// Everything in flash below this byte address belongs to the golden image
+pub const GOLDEN_IMAGE_END: u32 = USER_FLASH_ADDR - SECTOR_SIZE as u32;
+
+/// The byte address of the sector that says where the header of the flashed design is
+pub const METADATA_ADDR: u32 = GOLDEN_IMAGE_END;
+
+/// How much of the metadata sector we read back, which is plenty for the handful of entries in it
+const METADATA_WORDS: usize = 256;
+
+/// How long to wait between checks on a rebooting board that failed quickly, like when the
+/// network stack is up but nothing is listening yet
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Erasing a sector takes about a second, so flash writes need a lot more patience than register
+/// accesses. This is the least per-packet timeout we'll use for them.
+const FLASH_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// How far along [`program`] is
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// Writing sector `sector` (0-indexed) of `sectors`
+    Writing { sector: usize, sectors: usize },
+    /// Reading back sector `sector` (0-indexed) of `sectors` to check it
+    Verifying { sector: usize, sectors: usize },
+    /// Writing the `.fpg` header and the metadata sector
+    Metadata,
+    /// Everything is in flash, and we're telling the board to boot from it
+    Rebooting,
+}
+
+/// What to ask a rebooting board to see if it's back, see [`wait_until_responsive`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Probe {
+    /// Read `/temp`, which is tiny and always there
+    #[default]
+    Temp,
+    /// Read `/help`
+    Help,
+}
+
+/// The contents of the metadata sector, in the same format casperfpga uses. This is a run of
+/// `?key\tvalue` entries with decimal values, then `?end`, and the rest of the sector is left
+/// erased.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub sector_size: u32,
+    /// Byte address of the `.fpg` header in flash
+    pub header_start: u32,
+    /// Length of the header in bytes
+    pub header_length: u32,
+    /// Byte address of the bitstream in flash
+    pub bitstream_start: u32,
+    /// Length of the bitstream in bytes
+    pub bitstream_length: u32,
+    /// MD5 of the header, which casperfpga stores but we don't
+    pub header_md5: Option<[u8; 16]>,
+    /// MD5 of the bitstream, which casperfpga stores but we don't
+    pub bitstream_md5: Option<[u8; 16]>,
+}
+
+/// Parse 32 hex digits into an MD5 digest
+fn parse_md5(hex: &str) -> Option<[u8; 16]> {
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 16];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+impl Metadata {
+    /// The metadata for `fpg` flashed at [`USER_FLASH_ADDR`], with its header in the first sector
+    /// after the bitstream
+    pub fn new(fpg: &Fpg) -> Self {
+        let bitstream_sectors = sectors(&fpg.bitstream).len();
+        Metadata {
+            sector_size: SECTOR_SIZE as u32,
+            header_start: USER_FLASH_ADDR + (bitstream_sectors * SECTOR_SIZE) as u32,
+            header_length: fpg.header().len() as u32,
+            bitstream_start: USER_FLASH_ADDR,
+            bitstream_length: fpg.bitstream.len() as u32,
+            header_md5: None,
+            bitstream_md5: None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entries: Vec<(&str, String)> = vec![
+            ("sector_size", self.sector_size.to_string()),
+            ("header_start", self.header_start.to_string()),
+            ("header_length", self.header_length.to_string()),
+            ("prog_bitstream_start", self.bitstream_start.to_string()),
+            ("prog_bitstream_length", self.bitstream_length.to_string()),
+        ];
+        let md5s = [
+            ("md5_header", self.header_md5),
+            ("md5_bitstream", self.bitstream_md5),
+        ];
+        for (key, digest) in md5s {
+            if let Some(digest) = digest {
+                let hex = digest.iter().map(|b| format!("{:02x}", b)).collect();
+                entries.push((key, hex));
+            }
+        }
+        let mut text: String = entries
+            .iter()
+            .map(|(key, value)| format!("?{}\t{}", key, value))
+            .collect();
+        text.push_str("?end");
+        text.into_bytes()
+    }
+
+    /// Decode the start of the metadata sector, or `None` if it's erased
+    pub fn decode(bytes: &[u8]) -> Result<Option<Self>, Error> {
+        let end = bytes
+            .iter()
+            .position(|&b| b == 0xFF || b == 0)
+            .unwrap_or(bytes.len());
+        if end == 0 {
+            return Ok(None);
+        }
+        let text = std::str::from_utf8(&bytes[..end])?;
+        let text = text
+            .split_once("?end")
+            .and_then(|(entries, _)| entries.strip_prefix('?'))
+            .ok_or_else(|| Error::BadMetadata(text.to_owned()))?;
+        let mut entries = HashMap::new();
+        for entry in text.split('?') {
+            let (key, value) = entry
+                .split_once('\t')
+                .ok_or_else(|| Error::BadMetadata(entry.to_owned()))?;
+            entries.insert(key, value.trim());
+        }
+        let get = |key: &str| {
+            entries
+                .get(key)
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| Error::BadMetadata(format!("missing {}", key)))
+        };
+        let md5 = |key: &str| match entries.get(key) {
+            Some(hex) => parse_md5(hex)
+                .map(Some)
+                .ok_or_else(|| Error::BadMetadata(format!("{}={}", key, hex))),
+            None => Ok(None),
+        };
+        Ok(Some(Metadata {
+            sector_size: get("sector_size")?,
+            header_start: get("header_start")?,
+            header_length: get("header_length")?,
+            bitstream_start: get("prog_bitstream_start")?,
+            bitstream_length: get("prog_bitstream_length")?,
+            header_md5: md5("md5_header")?,
+            bitstream_md5: md5("md5_bitstream")?,
+        }))
+    }
+}
+
+/// `config`, but patient enough for flash writes
+pub(crate) fn flash_config(config: &Config) -> Config {
+    Config {
+        timeout: config.timeout.max(FLASH_TIMEOUT),
+        ..*config
+    }
+}
+
+/// Split `data` into sectors, padding the last one out with erased (0xFF) bytes
+fn sectors(data: &[u8]) -> Vec<Vec<u8>> {
+    data.chunks(SECTOR_SIZE)
+        .map(|chunk| {
+            let mut sector = chunk.to_vec();
+            sector.resize(SECTOR_SIZE, 0xFF);
+            sector
+        })
+        .collect()
+}
+
+/// The index of the first 4 byte word that differs between `expected` and `actual`
+pub(crate) fn first_mismatch(expected: &[u8], actual: &[u8]) -> Option<usize> {
+    expected
+        .chunks(4)
+        .zip(actual.chunks(4))
+        .position(|(a, b)| a != b)
+}
+
+/// Tell the board to reconfigure the FPGA from the image at byte address `address` in flash.
+/// The board reboots straight away, so it usually doesn't get the chance to finish the transfer,
+/// and we count not hearing back as success once it has acknowledged the request. Use
+/// [`wait_until_responsive`] to know when it's back.
+pub fn progdev(
+    address: u32,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let payload = protocol::progdev_payload(address).to_vec();
+    let mut transfer = Transfer::write("/progdev", payload, addr, *config);
+    match tftp::drive(&mut transfer, socket) {
+        Err(tftp::Error::Timeout(_)) if transfer.is_acknowledged() => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Reboot the board into the golden image, which is always there to fall back to if a user image
+/// is bad
+pub fn reboot_golden(
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    progdev(GOLDEN_IMAGE_ADDR, socket, addr, config)
+}
+
+/// Poll the board with `probe` until it answers or `timeout` runs out, returning how long it took.
+/// Every probe is a single try with no resends, so we keep asking at the pace of `config.timeout`.
+pub fn wait_until_responsive(
+    timeout: Duration,
+    probe: Probe,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Duration, Error> {
+    let config = Config {
+        retries: 0,
+        ..*config
+    };
+    let start = Instant::now();
+    loop {
+        let tried = Instant::now();
+        let result = match probe {
+            Probe::Temp => crate::temp(socket, addr, &config).map(|_| ()),
+            Probe::Help => crate::help(socket, addr, &config).map(|_| ()),
+        };
+        match result {
+            Ok(()) => return Ok(start.elapsed()),
+            // A board partway through booting can fail in all sorts of ways, like refusing the
+            // connection or answering with garbage, so anything short of an answer means not yet
+            Err(_) if start.elapsed() < timeout => {
+                thread::sleep(POLL_INTERVAL.saturating_sub(tried.elapsed()));
+            }
+            Err(_) => return Err(Error::NotResponding(timeout)),
+        }
+    }
+}
+
+/// Write one whole `sector` at byte address `address`, which must be sector aligned
+fn write_sector(
+    address: usize,
+    sector: &[u8],
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let filename = protocol::write_flash_filename(address / 4);
+    Ok(tftp::write(&filename, sector, socket, addr, config)?)
+}
+
+/// Read back the sector at byte address `address` and make sure it matches `sector`, reporting the
+/// address of the first word that doesn't
+fn verify_sector(
+    address: usize,
+    sector: &[u8],
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let readback = crate::read_flash(address / 4, SECTOR_SIZE / 4, socket, addr, config)?;
+    if readback.len() != sector.len() {
+        return Err(Error::Length {
+            expected: sector.len(),
+            received: readback.len(),
+        });
+    }
+    match first_mismatch(sector, &readback) {
+        Some(word) => Err(Error::FlashMismatch {
+            address: (address + word * 4) as u32,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The sectors touched by writing `len` bytes at byte address `start`. For each one, we get its
+/// address and the range of bytes within it that are being written.
+fn sector_spans(start: usize, len: usize) -> Vec<(usize, Range<usize>)> {
+    let end = start + len;
+    let first = start / SECTOR_SIZE * SECTOR_SIZE;
+    (first..end)
+        .step_by(SECTOR_SIZE)
+        .map(|sector| {
+            let lo = start.max(sector) - sector;
+            let hi = end.min(sector + SECTOR_SIZE) - sector;
+            (sector, lo..hi)
+        })
+        .collect()
+}
+
+/// Write `data` to the onboard flash
+/// `offset` is in increments of 4 byte words, just like `read_flash`
+///
+/// The flash is erased a whole sector at a time, so for any sector we only partially cover, we
+/// read the rest of it first and write it back along with `data`. With `verify`, every sector is
+/// read back afterwards, and we fail with the address of the first word that didn't stick.
+pub fn write_flash(
+    offset: usize,
+    data: &[u8],
+    verify: bool,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let start = offset * 4;
+    if start < GOLDEN_IMAGE_END as usize && !data.is_empty() {
+        return Err(Error::GoldenImage {
+            address: start as u32,
+        });
+    }
+    if start.saturating_add(data.len()) > FLASH_SIZE {
+        return Err(Error::PastEndOfFlash {
+            address: start,
+            length: data.len(),
+        });
+    }
+    let config = flash_config(config);
+    for (address, span) in sector_spans(start, data.len()) {
+        let chunk = &data[(address + span.start - start)..(address + span.end - start)];
+        let sector = if span.len() == SECTOR_SIZE {
+            chunk.to_vec()
+        } else {
+            let mut sector =
+                crate::read_flash(address / 4, SECTOR_SIZE / 4, socket, addr, &config)?;
+            if sector.len() != SECTOR_SIZE {
+                return Err(Error::Length {
+                    expected: SECTOR_SIZE,
+                    received: sector.len(),
+                });
+            }
+            sector[span].copy_from_slice(chunk);
+            sector
+        };
+        write_sector(address, &sector, socket, addr, &config)?;
+        if verify {
+            verify_sector(address, &sector, socket, addr, &config)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write `bitstream` into the user region of flash a sector at a time, reading each one back
+fn write_image<F>(
+    bitstream: &[u8],
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+    progress: &mut F,
+) -> Result<(), Error>
+where
+    F: FnMut(Progress),
+{
+    let sectors = sectors(bitstream);
+    let total = sectors.len();
+    for (i, sector) in sectors.iter().enumerate() {
+        let address = USER_FLASH_ADDR as usize + i * SECTOR_SIZE;
+        progress(Progress::Writing {
+            sector: i,
+            sectors: total,
+        });
+        write_sector(address, sector, socket, addr, config)?;
+        progress(Progress::Verifying {
+            sector: i,
+            sectors: total,
+        });
+        verify_sector(address, sector, socket, addr, config)?;
+    }
+    Ok(())
+}
+
+/// Make sure there's a bitstream to program, and that it fits in the user region of flash along
+/// with `header_length` bytes of header after it
+fn check_fits(bitstream: &[u8], header_length: usize) -> Result<(), Error> {
+    if bitstream.is_empty() {
+        return Err(Error::EmptyBitstream);
+    }
+    let mut length = bitstream.len();
+    if header_length > 0 {
+        length = sectors(bitstream).len() * SECTOR_SIZE + header_length;
+    }
+    let max = FLASH_SIZE - USER_FLASH_ADDR as usize;
+    if length > max {
+        return Err(Error::ImageTooLarge { length, max });
+    }
+    Ok(())
+}
+
+/// Program the board with the raw bitstream `bitstream`. This writes it into the user region of
+/// flash a sector at a time, reads each sector back to check it, and then reboots the board into
+/// the new image. `progress` is called as we go.
+pub fn program<F>(
+    bitstream: &[u8],
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+    mut progress: F,
+) -> Result<(), Error>
+where
+    F: FnMut(Progress),
+{
+    check_fits(bitstream, 0)?;
+    let config = flash_config(config);
+    write_image(bitstream, socket, addr, &config, &mut progress)?;
+    progress(Progress::Rebooting);
+    progdev(USER_FLASH_ADDR, socket, addr, &config)
+}
+
+/// Program the board with the bitstream from `fpg`, like [`program`], but also store its header
+/// and metadata so [`read_fpg`] can find it after the reboot
+pub fn program_fpg<F>(
+    fpg: &Fpg,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+    mut progress: F,
+) -> Result<(), Error>
+where
+    F: FnMut(Progress),
+{
+    check_fits(&fpg.bitstream, fpg.header().len())?;
+    let config = flash_config(config);
+    write_image(&fpg.bitstream, socket, addr, &config, &mut progress)?;
+    progress(Progress::Metadata);
+    write_metadata(fpg, socket, addr, &config)?;
+    progress(Progress::Rebooting);
+    progdev(USER_FLASH_ADDR, socket, addr, &config)
+}
+
+/// Write the header of `fpg` into flash after its bitstream and point the metadata sector at it.
+/// This doesn't touch the bitstream itself.
+pub fn write_metadata(
+    fpg: &Fpg,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let metadata = Metadata::new(fpg);
+    let header = fpg.header();
+    write_flash(
+        metadata.header_start as usize / 4,
+        header.as_bytes(),
+        true,
+        socket,
+        addr,
+        config,
+    )?;
+    // Write the whole sector so nothing from older metadata is left after ours
+    let mut sector = metadata.encode();
+    sector.resize(SECTOR_SIZE, 0xFF);
+    write_flash(
+        METADATA_ADDR as usize / 4,
+        &sector,
+        true,
+        socket,
+        addr,
+        config,
+    )
+}
+
+/// Read the metadata sector, or `None` if nothing has been written there
+pub fn read_metadata(
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Option<Metadata>, Error> {
+    let bytes = crate::read_flash(
+        METADATA_ADDR as usize / 4,
+        METADATA_WORDS,
+        socket,
+        addr,
+        config,
+    )?;
+    Metadata::decode(&bytes)
+}
+
+/// Read back the header of the design in flash, as stored by [`program_fpg`]. The bitstream is
+/// left empty. Returns `None` if there's no metadata in flash.
+pub fn read_fpg(
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Option<Fpg>, Error> {
+    let metadata = match read_metadata(socket, addr, config)? {
+        Some(metadata) => metadata,
+        None => return Ok(None),
+    };
+    let length = metadata.header_length as usize;
+    let mut header = crate::read_flash(
+        metadata.header_start as usize / 4,
+        (length + 3) / 4,
+        socket,
+        addr,
+        config,
+    )?;
+    if header.len() < length {
+        return Err(Error::Length {
+            expected: length,
+            received: header.len(),
+        });
+    }
+    header.truncate(length);
+    Ok(Some(Fpg::parse(&header)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tftp::{tests::next_packet, Payload};
+
+    #[test]
+    fn test_sectors() {
+        let bitstream = vec![0xAB; SECTOR_SIZE + 3];
+        let sectors = sectors(&bitstream);
+        assert_eq!(sectors.len(), 2);
+        assert!(sectors[0].iter().all(|&b| b == 0xAB));
+        assert_eq!(sectors[1].len(), SECTOR_SIZE);
+        assert_eq!(sectors[1][..4], [0xAB, 0xAB, 0xAB, 0xFF]);
+    }
+
+    #[test]
+    fn test_first_mismatch() {
+        let expected = [1, 2, 3, 4, 5, 6, 7, 8];
+        assert_eq!(first_mismatch(&expected, &expected), None);
+        assert_eq!(
+            first_mismatch(&expected, &[1, 2, 3, 4, 5, 6, 0, 8]),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn test_flash_config() {
+        let config = Config {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        assert_eq!(flash_config(&config).timeout, FLASH_TIMEOUT);
+    }
+
+    #[test]
+    fn test_sector_spans() {
+        // Exactly one sector
+        assert_eq!(
+            sector_spans(SECTOR_SIZE, SECTOR_SIZE),
+            vec![(SECTOR_SIZE, 0..SECTOR_SIZE)]
+        );
+        // Straddling a boundary
+        assert_eq!(
+            sector_spans(SECTOR_SIZE - 4, 8),
+            vec![(0, SECTOR_SIZE - 4..SECTOR_SIZE), (SECTOR_SIZE, 0..4)]
+        );
+        assert!(sector_spans(SECTOR_SIZE, 0).is_empty());
+    }
+
+    #[test]
+    fn test_golden_guard() {
+        // We should bail before ever touching the network
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = "127.0.0.1:9".parse().unwrap();
+        let result = write_flash(0x100, &[0; 4], true, &mut socket, addr, &Config::default());
+        assert!(matches!(result, Err(Error::GoldenImage { address: 0x400 })));
+    }
+
+    #[test]
+    fn test_metadata() {
+        let fpg = Fpg {
+            bitstream: vec![0; SECTOR_SIZE + 1],
+            ..Default::default()
+        };
+        let metadata = Metadata::new(&fpg);
+        assert_eq!(
+            metadata.header_start,
+            USER_FLASH_ADDR + 2 * SECTOR_SIZE as u32
+        );
+        assert_eq!(metadata.header_length as usize, fpg.header().len());
+        // Erased flash after it, just like in the sector
+        let mut bytes = metadata.encode();
+        bytes.extend_from_slice(&[0xFF; 8]);
+        assert_eq!(Metadata::decode(&bytes).unwrap(), Some(metadata));
+        assert_eq!(Metadata::decode(&[0xFF; 16]).unwrap(), None);
+        assert!(matches!(
+            Metadata::decode(b"?sector_size\t65536?end"),
+            Err(Error::BadMetadata(_))
+        ));
+        assert!(matches!(
+            Metadata::decode(b"?sector_size\t65536"),
+            Err(Error::BadMetadata(_))
+        ));
+    }
+
+    /// The metadata sector as casperfpga leaves it after programming a 115000 byte bitstream
+    const CASPERFPGA_METADATA: &[u8] = b"?sector_size\t65536?header_start\t8519680\
+        ?header_length\t1433?prog_bitstream_start\t8388608?prog_bitstream_length\t115000\
+        ?md5_header\t9e107d9d372bb6826bd81d3542a419d6\
+        ?md5_bitstream\td41d8cd98f00b204e9800998ecf8427e?end\xff\xff\xff\xff";
+
+    #[test]
+    fn test_casperfpga_metadata() {
+        let metadata = Metadata::decode(CASPERFPGA_METADATA).unwrap().unwrap();
+        assert_eq!(metadata.sector_size as usize, SECTOR_SIZE);
+        assert_eq!(
+            metadata.header_start,
+            USER_FLASH_ADDR + 2 * SECTOR_SIZE as u32
+        );
+        assert_eq!(metadata.header_length, 1433);
+        assert_eq!(metadata.bitstream_start, USER_FLASH_ADDR);
+        assert_eq!(metadata.bitstream_length, 115000);
+        assert_eq!(metadata.header_md5.unwrap()[..2], [0x9e, 0x10]);
+        assert_eq!(metadata.bitstream_md5.unwrap()[15], 0x7e);
+        let end = CASPERFPGA_METADATA.len() - 4;
+        assert_eq!(metadata.encode(), CASPERFPGA_METADATA[..end]);
+    }
+
+    #[test]
+    fn test_progdev_reboots_mid_transfer() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            server
+                .send_to(&Payload::Ack { block: 0 }.pack(), from)
+                .unwrap();
+            // Then the board reboots without acknowledging the address
+            next_packet(&server);
+        });
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            timeout: Duration::from_millis(20),
+            retries: 2,
+            ..Default::default()
+        };
+        progdev(USER_FLASH_ADDR, &mut socket, addr, &config).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_until_responsive() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            // Still booting, so the first request goes nowhere
+            next_packet(&server);
+            let (_, from) = next_packet(&server);
+            let data = Payload::Data {
+                block: 1,
+                data: 42.5f32.to_be_bytes().to_vec(),
+            };
+            server.send_to(&data.pack(), from).unwrap();
+            next_packet(&server);
+        });
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let waited = wait_until_responsive(
+            Duration::from_secs(5),
+            Probe::Temp,
+            &mut socket,
+            addr,
+            &config,
+        )
+        .unwrap();
+        assert!(waited >= Duration::from_millis(50));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_gives_up() {
+        // Nobody is listening here
+        let addr = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            timeout: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let result = wait_until_responsive(
+            Duration::from_millis(100),
+            Probe::Help,
+            &mut socket,
+            addr,
+            &config,
+        );
+        assert!(matches!(result, Err(Error::NotResponding(_))));
+        assert!(result.unwrap_err().is_timeout());
+    }
+}
@@ -0,0 +1,318 @@
+//! `.fpg` files, which the CASPER toolflow produces for programming a board.
+//!
+//! An `.fpg` is a text header followed by the bitstream. Every header line starts with `?`, and the
+//! ones we care about are
+//!
+//! ```text
+//! ?register   NAME    ADDRESS LENGTH
+//! ?meta       BLOCK   TAG     KEY     VALUE
+//! ?quit
+//! ```
+//!
+//! with fields separated by tabs (older files use spaces) and numbers in `0x` prefixed hex.
+//! `?register` lines are the devices the gateware will list in `/listdev`. `?meta` lines attach
+//! key/value settings to a block, with the block's tag (like `xps:sw_reg`) saying what kind of
+//! block it is. Spaces in values are escaped as `\_`. The bitstream, usually gzipped, starts right
+//! after the newline that ends `?quit`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, Read},
+};
+
+/// Errors from parsing a malformed `.fpg` file
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("The header never ended with ?quit")]
+    MissingQuit,
+    #[error("Line {line} of the header is malformed: {text}")]
+    BadLine { line: usize, text: String },
+    #[error("Line {line} of the header has a bad number: {text}")]
+    BadNumber { line: usize, text: String },
+    #[error("The header wasn't valid UTF-8")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("The bitstream couldn't be decompressed")]
+    Gzip(#[from] io::Error),
+}
+
+/// The magic bytes every gzip stream starts with
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A device the gateware exposes on the bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// Byte address on the bus
+    pub address: u32,
+    /// Length in bytes
+    pub length: u32,
+    /// The tag of the block the device belongs to (like `xps:sw_reg`), if it has any metadata
+    pub kind: Option<String>,
+}
+
+/// The metadata attached to one block in the design
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Block {
+    /// What kind of block this is, like `xps:sw_reg` or `xps:snap`
+    pub tag: String,
+    /// The block's settings
+    pub params: BTreeMap<String, String>,
+}
+
+/// How a board's `/listdev` differs from what an `.fpg` says should be there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// The file has a device the board doesn't
+    Missing(String),
+    /// The board has a device the file doesn't
+    Unexpected(String),
+    /// Both have the device, but at a different (address,length)
+    Moved {
+        name: String,
+        file: (u32, u32),
+        board: (u32, u32),
+    },
+}
+
+/// A parsed `.fpg` file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fpg {
+    /// Every `?register`, by name
+    pub devices: BTreeMap<String, Device>,
+    /// Every block with `?meta` lines, by name
+    pub meta: BTreeMap<String, Block>,
+    /// The raw (decompressed) bitstream
+    pub bitstream: Vec<u8>,
+}
+
+/// Split a header line into fields on tabs, or on runs of spaces if there aren't any
+fn fields(line: &str) -> Vec<&str> {
+    if line.contains('\t') {
+        line.split('\t').filter(|f| !f.is_empty()).collect()
+    } else {
+        line.split_whitespace().collect()
+    }
+}
+
+fn parse_number(field: &str, line: usize) -> Result<u32, Error> {
+    let digits = field
+        .strip_prefix("0x")
+        .or_else(|| field.strip_prefix("0X"))
+        .unwrap_or(field);
+    u32::from_str_radix(digits, 16).map_err(|_| Error::BadNumber {
+        line,
+        text: field.to_owned(),
+    })
+}
+
+/// Decompress `bytes` if they're gzipped, otherwise take them as they are
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, Error> {
+    if !bytes.starts_with(&GZIP_MAGIC) {
+        return Ok(bytes.to_vec());
+    }
+    let mut bitstream = Vec::new();
+    flate2::read::GzDecoder::new(bytes).read_to_end(&mut bitstream)?;
+    Ok(bitstream)
+}
+
+impl Fpg {
+    /// Parse the contents of an `.fpg` file
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        let mut fpg = Fpg::default();
+        let mut rest = bytes;
+        let mut line = 0;
+        loop {
+            line += 1;
+            let end = rest
+                .iter()
+                .position(|&b| b == b'\n')
+                .ok_or(Error::MissingQuit)?;
+            let text = std::str::from_utf8(&rest[..end])?.trim_end_matches('\r');
+            rest = &rest[end + 1..];
+            if text.trim() == "?quit" {
+                break;
+            }
+            fpg.parse_line(text, line)?;
+        }
+        // Metadata can come before or after the register it describes, so we match them up last
+        for (name, device) in fpg.devices.iter_mut() {
+            device.kind = fpg.meta.get(name).map(|block| block.tag.clone());
+        }
+        fpg.bitstream = decompress(rest)?;
+        Ok(fpg)
+    }
+
+    fn parse_line(&mut self, text: &str, line: usize) -> Result<(), Error> {
+        let bad = || Error::BadLine {
+            line,
+            text: text.to_owned(),
+        };
+        match fields(text).as_slice() {
+            ["?register", name, address, length] => {
+                self.devices.insert(
+                    name.to_string(),
+                    Device {
+                        address: parse_number(address, line)?,
+                        length: parse_number(length, line)?,
+                        kind: None,
+                    },
+                );
+            }
+            ["?meta", block, tag, key, value @ ..] => {
+                let value = value.join(" ").replace("\\_", " ");
+                let block = self.meta.entry(block.to_string()).or_default();
+                block.tag = tag.to_string();
+                block.params.insert(key.to_string(), value);
+            }
+            ["?register", ..] | ["?meta", ..] => return Err(bad()),
+            // The shebang, blank lines, and commands we don't need like `?uploadbin`
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Write out the header, everything up to and including `?quit`, in the same format the
+    /// toolflow does. Parsing it back gets us this `Fpg` without its bitstream.
+    pub fn header(&self) -> String {
+        let mut header = String::from("#!/bin/kcpfpg\n?uploadbin\n");
+        for (name, device) in &self.devices {
+            header += &format!(
+                "?register\t{}\t{:#x}\t{:#x}\n",
+                name, device.address, device.length
+            );
+        }
+        for (name, block) in &self.meta {
+            for (key, value) in &block.params {
+                let value = value.replace(' ', "\\_");
+                header += &format!("?meta\t{}\t{}\t{}\t{}\n", name, block.tag, key, value);
+            }
+        }
+        header + "?quit\n"
+    }
+
+    /// The devices as (addr,length), in the same shape as [`crate::listdev`] returns them
+    pub fn listdev(&self) -> HashMap<String, (u32, u32)> {
+        self.devices
+            .iter()
+            .map(|(name, device)| (name.clone(), (device.address, device.length)))
+            .collect()
+    }
+
+    /// Compare a board's `/listdev` against the devices in this file. An empty result means the
+    /// board is very likely running this design.
+    pub fn compare(&self, listdev: &HashMap<String, (u32, u32)>) -> Vec<Difference> {
+        let mut differences = vec![];
+        for (name, device) in &self.devices {
+            let file = (device.address, device.length);
+            match listdev.get(name) {
+                None => differences.push(Difference::Missing(name.clone())),
+                Some(&board) if board != file => differences.push(Difference::Moved {
+                    name: name.clone(),
+                    file,
+                    board,
+                }),
+                _ => {}
+            }
+        }
+        let mut unexpected: Vec<_> = listdev
+            .keys()
+            .filter(|name| !self.devices.contains_key(*name))
+            .cloned()
+            .collect();
+        unexpected.sort();
+        differences.extend(unexpected.into_iter().map(Difference::Unexpected));
+        differences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    const HEADER: &str = "#!/bin/kcpfpg\n\
+        ?uploadbin\n\
+        ?register\tsys_board_id\t0x0\t0x4\n\
+        ?register\tgain\t0x10000\t0x4\n\
+        ?meta\tgain\txps:sw_reg\tio_dir\tFrom\\_Processor\n\
+        ?meta\tgain\txps:sw_reg\tbitwidths\t32\n\
+        ?quit\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut bytes = HEADER.as_bytes().to_vec();
+        bytes.extend_from_slice(&gzip(&[0xAA, 0x99, 0x55, 0x66]));
+        let fpg = Fpg::parse(&bytes).unwrap();
+        assert_eq!(fpg.bitstream, [0xAA, 0x99, 0x55, 0x66]);
+        assert_eq!(
+            fpg.devices["gain"],
+            Device {
+                address: 0x10000,
+                length: 4,
+                kind: Some("xps:sw_reg".to_owned())
+            }
+        );
+        assert_eq!(fpg.devices["sys_board_id"].kind, None);
+        assert_eq!(fpg.meta["gain"].params["io_dir"], "From Processor");
+        assert_eq!(fpg.meta["gain"].params["bitwidths"], "32");
+    }
+
+    #[test]
+    fn test_uncompressed_and_spaces() {
+        let bytes = b"?register gain 0x10 0x4\n?quit\n\x01\x02";
+        let fpg = Fpg::parse(bytes).unwrap();
+        assert_eq!(fpg.devices["gain"].address, 0x10);
+        assert_eq!(fpg.bitstream, [1, 2]);
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(matches!(
+            Fpg::parse(b"?register\tgain\t0x10\n?quit\n"),
+            Err(Error::BadLine { line: 1, .. })
+        ));
+        assert!(matches!(
+            Fpg::parse(b"\n?register\tgain\t0x10\tfour\n?quit\n"),
+            Err(Error::BadNumber { line: 2, .. })
+        ));
+        assert!(matches!(
+            Fpg::parse(b"?register\tgain\t0x10\t0x4\n"),
+            Err(Error::MissingQuit)
+        ));
+    }
+
+    #[test]
+    fn test_compare() {
+        let fpg = Fpg::parse(HEADER.as_bytes()).unwrap();
+        assert!(fpg.compare(&fpg.listdev()).is_empty());
+        let mut board = fpg.listdev();
+        board.remove("sys_board_id");
+        board.insert("gain".to_owned(), (0x20000, 4));
+        board.insert("extra".to_owned(), (0x30000, 4));
+        assert_eq!(
+            fpg.compare(&board),
+            vec![
+                Difference::Moved {
+                    name: "gain".to_owned(),
+                    file: (0x10000, 4),
+                    board: (0x20000, 4)
+                },
+                Difference::Missing("sys_board_id".to_owned()),
+                Difference::Unexpected("extra".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut fpg = Fpg::parse(HEADER.as_bytes()).unwrap();
+        fpg.bitstream = vec![];
+        assert_eq!(Fpg::parse(fpg.header().as_bytes()).unwrap(), fpg);
+    }
+}
@@ -1,67 +1,149 @@
-mod csl;
+//! A client for TAPCP, the TFTP-based protocol CASPER boards speak for register and flash access.
+//!
+//! Every operation takes a bound (but not connected) [`UdpSocket`] and the address of the board's
+//! TFTP server (usually port 69). The board answers each request from a fresh port, so the socket
+//! must be free to hear from it. [`Tapcp`] wraps all of that up into a handle on a single board.
+//!
+//! With the `tokio` feature, the `asynchronous` module has the same operations over
+//! `tokio::net::UdpSocket`. With the `sim` feature, the `sim` module has a simulated board to test
+//! against.
+
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+pub mod bram;
+mod client;
+pub mod csl;
+pub mod flash;
+pub mod fpg;
+mod protocol;
+pub mod register;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod snapshot;
 pub mod tftp;
 
-use std::{collections::HashMap, ffi::CStr, net::UdpSocket};
+pub use client::{Tapcp, TapcpBuilder};
+pub use flash::{
+    progdev, program, program_fpg, read_fpg, reboot_golden, wait_until_responsive, write_flash,
+};
+pub use register::{
+    read_field, read_fixed, read_register, write_field, write_fixed, write_register,
+};
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+};
+
+use tftp::{Config, Mode};
+
+/// Errors that can be thrown from TAPCP interactions
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Tftp(#[from] tftp::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("The gateware doesn't have a device named {0}")]
+    UnknownDevice(String),
+    #[error("We expected {expected} bytes back, but received {received}")]
+    Length { expected: usize, received: usize },
+    #[error("The device list was malformed: {0}")]
+    Csl(#[from] csl::Error),
+    #[error("The response wasn't valid UTF-8")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("The timeout must be nonzero")]
+    ZeroTimeout,
+    #[error("{0} didn't resolve to any addresses")]
+    Resolve(String),
+    #[error("Flash at {address:#x} didn't read back what we wrote")]
+    FlashMismatch { address: u32 },
+    #[error("Writing flash at {address:#x} would clobber the golden image")]
+    GoldenImage { address: u32 },
+    #[error("Writing {length} bytes to flash at {address:#x} would run past the end of it")]
+    PastEndOfFlash { address: usize, length: usize },
+    #[error("There's no bitstream to program")]
+    EmptyBitstream,
+    #[error("The image takes {length} bytes, but the user region of flash only holds {max}")]
+    ImageTooLarge { length: usize, max: usize },
+    #[error("The .fpg was malformed: {0}")]
+    Fpg(#[from] fpg::Error),
+    #[error("The flash metadata was malformed: {0}")]
+    BadMetadata(String),
+    #[error("The board didn't answer within {0:?}")]
+    NotResponding(std::time::Duration),
+    #[error("Byte offset {offset} isn't on a word boundary")]
+    Unaligned { offset: usize },
+    #[error("{value} doesn't fit in a fixed-point register holding {min} to {max}")]
+    FixedRange { value: f64, min: f64, max: f64 },
+    #[error("The register has no field named {0}")]
+    UnknownField(String),
+    #[error("{value} doesn't fit in field {field}, which holds {min} to {max}")]
+    FieldRange {
+        field: String,
+        value: i64,
+        min: i64,
+        max: i64,
+    },
+    #[error("The snapshot didn't finish capturing within {0:?}")]
+    CaptureTimeout(std::time::Duration),
+}
 
-use anyhow::bail;
-use tftp::Mode;
+impl Error {
+    /// Whether this was the board not answering at all
+    pub fn is_timeout(&self) -> bool {
+        matches!(
+            self,
+            Error::Tftp(tftp::Error::Timeout(_)) | Error::NotResponding(_)
+        )
+    }
+}
 
 /// Gets the temperature of the remote device in Celsius
-pub fn temp(socket: &mut UdpSocket) -> anyhow::Result<f32> {
-    let bytes = tftp::read("/temp", socket, Mode::Octet)?;
-    Ok(f32::from_be_bytes(bytes[..4].try_into()?))
+pub fn temp(socket: &mut UdpSocket, addr: SocketAddr, config: &Config) -> Result<f32, Error> {
+    let bytes = tftp::read("/temp", socket, addr, Mode::Octet, config)?;
+    protocol::decode_temp(&bytes)
 }
 
 /// Gets the list of top level commands (as a string)
-pub fn help(socket: &mut UdpSocket) -> anyhow::Result<String> {
-    let bytes = tftp::read("/help", socket, Mode::NetASCII)?;
-    Ok(std::str::from_utf8(&bytes)?.to_string())
+pub fn help(socket: &mut UdpSocket, addr: SocketAddr, config: &Config) -> Result<String, Error> {
+    let bytes = tftp::read("/help", socket, addr, Mode::NetASCII, config)?;
+    protocol::decode_help(&bytes)
 }
 
 /// Gets the list of all devices supported by the currently running gateware
 /// Returns a hash map from device name to (addr,length)
-pub fn listdev(socket: &mut UdpSocket) -> anyhow::Result<HashMap<String, (u32, u32)>> {
-    // Create the hash map we'll be constructing to hold the device list
-    let mut dev_map = HashMap::new();
-
-    let bytes = tftp::read("/listdev", socket, Mode::Octet)?;
-    // Bytes back from this are stored as CSL, so we'll use Dave's C program to uncompress it
-    // The CSL lib has internal state for some reason
-
-    // The first two bytes are the length, but we don't care because that's part of the UDP payload
-    // Safety: bytes is valid at this point because it's rust memory
-    unsafe { csl::csl_iter_init(bytes[2..].as_ptr()) }
-
-    // Now, we have to use the CSL iterator to traverse the list
-    // Create a ptr to null that will be updated by `csl_iter_next`
-    let mut key_ptr = std::ptr::null();
-
-    loop {
-        // Safety: key_ptr is valid because it's rust memory
-        let value_ptr = unsafe { csl::csl_iter_next(&mut key_ptr) };
-
-        if value_ptr.is_null() {
-            break;
-        }
-
-        // Now key *should* be valid
-        // Safety: We're trusting Dave gives us ptrs to valid ASCII
-        // and we can safely reinterpret the *const u8 and *const i8 because they share a size
-        let key = unsafe { CStr::from_ptr(key_ptr as *const i8) }
-            .to_str()?
-            .to_owned();
-
-        // Safety: The "spec" says this will be 8 bytes
-        let value = unsafe { std::slice::from_raw_parts(value_ptr, 8) };
-
-        // The first 4 byte word is the offset (address) and the second is the length
-        let addr = u32::from_be_bytes(value[..4].try_into()?);
-        let length = u32::from_be_bytes(value[4..].try_into()?);
-
-        // Finally, push this all to our hash map
-        dev_map.insert(key, (addr, length));
-    }
-    Ok(dev_map)
+pub fn listdev(
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<HashMap<String, (u32, u32)>, Error> {
+    let bytes = tftp::read("/listdev", socket, addr, Mode::Octet, config)?;
+    protocol::decode_listdev(&bytes)
+}
+
+/// Look up the (addr,length) of a single device, without decoding the whole device list.
+/// Returns `None` if the gateware doesn't have it.
+pub fn find_device(
+    device: &str,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Option<(u32, u32)>, Error> {
+    let bytes = tftp::read("/listdev", socket, addr, Mode::Octet, config)?;
+    protocol::find_device(&bytes, device)
+}
+
+/// Find which device's memory contains the bus address `address`.
+/// Returns the name of the device and how many bytes into it `address` is.
+pub fn device_at(
+    address: u32,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Option<(String, u32)>, Error> {
+    let bytes = tftp::read("/listdev", socket, addr, Mode::Octet, config)?;
+    protocol::device_at(&bytes, address)
 }
 
 /// Read memory associated with the gateware device `device`
@@ -72,15 +154,13 @@ pub fn read_device(
     offset: usize,
     n: usize,
     socket: &mut UdpSocket,
-) -> anyhow::Result<Vec<u8>> {
-    // To start the request, we need to form the filename string, defined by the TAPCP
-    // spec as - `/dev/DEV_NAME[.WORD_OFFSET[.NWORDS]]` with WORD_OFFSET and NWORDs in hexadecimal
-    let filename = format!("/dev/{}.{:x}.{:x}", device, offset, n);
-    let bytes = tftp::read(&filename, socket, Mode::Octet)?;
-    if n != 0 && bytes.len() != n * 4 {
-        bail!("We did not receive the number of bytes we expected");
-    }
-    Ok(bytes)
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let filename = protocol::read_device_filename(device, offset, n);
+    let bytes = tftp::read(&filename, socket, addr, Mode::Octet, config)
+        .map_err(|e| protocol::device_error(device, e))?;
+    protocol::check_device_read(bytes, n)
 }
 
 /// Write bytes to the device named `device`
@@ -89,21 +169,25 @@ pub fn write_device(
     offset: usize,
     data: &[u8],
     socket: &mut UdpSocket,
-) -> anyhow::Result<()> {
-    // To start the request, we need to form the filename string, defined by the TAPCP
-    // spec as - `/dev/DEV_NAME[.WORD_OFFSET]` with WORD_OFFSET and NWORDs in hexadecimal
-    let filename = format!("/dev/{}.{:x}", device, offset);
-    // Then do it
-    tftp::write(&filename, data, socket)
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let filename = protocol::write_device_filename(device, offset);
+    tftp::write(&filename, data, socket, addr, config)
+        .map_err(|e| protocol::device_error(device, e))
 }
 
 /// Read memory from the onboard flash
 /// `offset` and `n` are in increments of 4 byte words, just like `read_device`
-pub fn read_flash(offset: usize, n: usize, socket: &mut UdpSocket) -> anyhow::Result<Vec<u8>> {
-    // spec as - `/flash.WORD_OFFSET[.NWORDS]` with WORD_OFFSET and NWORDs in hexadecimal
-    let filename = format!("/flash.{:x}.{:x}", offset, n);
-    let bytes = tftp::read(&filename, socket, Mode::Octet)?;
-    Ok(bytes)
+pub fn read_flash(
+    offset: usize,
+    n: usize,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let filename = protocol::read_flash_filename(offset, n);
+    Ok(tftp::read(&filename, socket, addr, Mode::Octet, config)?)
 }
 
 #[cfg(test)]
@@ -112,14 +196,16 @@ mod tests {
 
     #[test]
     fn test_roundtrip() {
-        let mut s = UdpSocket::bind("0.0.0.0:0").unwrap();
-        s.connect("192.168.0.3:69").unwrap();
+        let sim = sim::Simulator::start(sim::Board::new()).unwrap();
+        let mut s = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sim.addr();
         let device = "sys_scratchpad";
         let payload = [1, 2, 3, 4];
         // Write bytes
-        write_device(device, 0, &payload, &mut s).unwrap();
+        let config = Config::default();
+        write_device(device, 0, &payload, &mut s, addr, &config).unwrap();
         // Read back
-        let bytes = read_device(device, 0, 1, &mut s).unwrap();
+        let bytes = read_device(device, 0, 1, &mut s, addr, &config).unwrap();
         assert_eq!(bytes, payload);
     }
 }
@@ -0,0 +1,230 @@
+//! The TAPCP request filenames and response formats, independent of how the bytes get moved
+//! around. Both the blocking and async clients are built out of these.
+
+use std::collections::HashMap;
+
+use crate::{
+    csl,
+    tftp::{self, ErrorCode},
+    Error,
+};
+
+/// The filename for reading `n` words at word `offset` into `device`, defined by the TAPCP
+/// spec as - `/dev/DEV_NAME[.WORD_OFFSET[.NWORDS]]` with WORD_OFFSET and NWORDs in hexadecimal
+pub(crate) fn read_device_filename(device: &str, offset: usize, n: usize) -> String {
+    format!("/dev/{}.{:x}.{:x}", device, offset, n)
+}
+
+/// The filename for writing at word `offset` into `device`, defined by the TAPCP
+/// spec as - `/dev/DEV_NAME[.WORD_OFFSET]` with WORD_OFFSET in hexadecimal
+pub(crate) fn write_device_filename(device: &str, offset: usize) -> String {
+    format!("/dev/{}.{:x}", device, offset)
+}
+
+/// The filename for reading `n` words at word `offset` into flash, defined by the TAPCP
+/// spec as - `/flash.WORD_OFFSET[.NWORDS]` with WORD_OFFSET and NWORDs in hexadecimal
+pub(crate) fn read_flash_filename(offset: usize, n: usize) -> String {
+    format!("/flash.{:x}.{:x}", offset, n)
+}
+
+/// The filename for writing at word `offset` into flash, defined by the TAPCP
+/// spec as - `/flash.WORD_OFFSET` with WORD_OFFSET in hexadecimal
+pub(crate) fn write_flash_filename(offset: usize) -> String {
+    format!("/flash.{:x}", offset)
+}
+
+/// What to write to `/progdev` to boot from byte address `address` in flash. The firmware talks
+/// to the flash in 32-bit addressing mode, so it wants the address shifted down a byte.
+pub(crate) fn progdev_payload(address: u32) -> [u8; 4] {
+    (address >> 8).to_be_bytes()
+}
+
+/// The temperature in Celsius is a single big-endian float
+pub(crate) fn decode_temp(bytes: &[u8]) -> Result<f32, Error> {
+    let word = bytes.get(..4).ok_or(Error::Length {
+        expected: 4,
+        received: bytes.len(),
+    })?;
+    Ok(f32::from_be_bytes(
+        word.try_into().expect("We just took 4 bytes"),
+    ))
+}
+
+pub(crate) fn decode_help(bytes: &[u8]) -> Result<String, Error> {
+    Ok(std::str::from_utf8(bytes)?.to_string())
+}
+
+/// Make sure a device read of `n` words came back with as many bytes as we asked for
+pub(crate) fn check_device_read(bytes: Vec<u8>, n: usize) -> Result<Vec<u8>, Error> {
+    if n != 0 && bytes.len() != n * 4 {
+        return Err(Error::Length {
+            expected: n * 4,
+            received: bytes.len(),
+        });
+    }
+    Ok(bytes)
+}
+
+/// The board answers requests for devices it doesn't have with "File not found", so we turn that
+/// into something more specific
+pub(crate) fn device_error(device: &str, error: tftp::Error) -> Error {
+    match error {
+        tftp::Error::ErrorResponse(ErrorCode::NotFound, _) => {
+            Error::UnknownDevice(device.to_owned())
+        }
+        e => e.into(),
+    }
+}
+
+/// Pull the device's (addr,length) out of an 8 byte `/listdev` payload
+fn device_payload(value: &[u8]) -> (u32, u32) {
+    // The first 4 byte word is the offset (address) and the second is the length
+    let addr = u32::from_be_bytes(value[..4].try_into().expect("Sliced to 4 bytes"));
+    let length = u32::from_be_bytes(value[4..].try_into().expect("Sliced to 4 bytes"));
+    (addr, length)
+}
+
+/// Check the header of a `/listdev` response, returning the CSL inside it
+fn listdev_csl(bytes: &[u8]) -> Result<&[u8], Error> {
+    // The first two bytes are the length, but we don't care because that's part of the UDP payload
+    // Bytes after that are stored as CSL
+    let csl = bytes.get(2..).unwrap_or_default();
+    // Every payload is two 4 byte words
+    let payload_size = csl::iter(csl)?.payload_size();
+    if payload_size != 8 {
+        return Err(Error::Length {
+            expected: 8,
+            received: payload_size,
+        });
+    }
+    Ok(csl)
+}
+
+/// Turn the response to `/listdev` into a hash map from device name to (addr,length)
+pub(crate) fn decode_listdev(bytes: &[u8]) -> Result<HashMap<String, (u32, u32)>, Error> {
+    csl::iter(listdev_csl(bytes)?)?
+        .map(|entry| {
+            let (key, value) = entry?;
+            Ok((key, device_payload(value)))
+        })
+        .collect()
+}
+
+/// Find the (addr,length) of `device` in the response to `/listdev`
+pub(crate) fn find_device(bytes: &[u8], device: &str) -> Result<Option<(u32, u32)>, Error> {
+    Ok(csl::find_key(listdev_csl(bytes)?, device)?.map(device_payload))
+}
+
+/// Whether `address` is within the memory of a device at (addr,length)
+pub(crate) fn contains(address: u32, (addr, length): (u32, u32)) -> bool {
+    address >= addr && address - addr < length
+}
+
+/// Find the device in the response to `/listdev` whose memory contains `address`, returning its
+/// name and how many bytes into it `address` is
+pub(crate) fn device_at(bytes: &[u8], address: u32) -> Result<Option<(String, u32)>, Error> {
+    let found = csl::find_by_payload(listdev_csl(bytes)?, |value| {
+        contains(address, device_payload(value))
+    })?
+    .next()
+    .transpose()?;
+    Ok(found.map(|(device, value)| (device, address - device_payload(value).0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_temp() {
+        assert!(matches!(
+            decode_temp(&[0x42, 0x2a]),
+            Err(Error::Length {
+                expected: 4,
+                received: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_short_device_read() {
+        assert!(matches!(
+            check_device_read(vec![0; 4], 2),
+            Err(Error::Length {
+                expected: 8,
+                received: 4
+            })
+        ));
+        // Asking for 0 words gets us whatever is there
+        assert!(check_device_read(vec![0; 12], 0).is_ok());
+    }
+
+    #[test]
+    fn test_device_error() {
+        let not_found = tftp::Error::ErrorResponse(ErrorCode::NotFound, "".to_owned());
+        assert!(matches!(
+            device_error("foo", not_found),
+            Error::UnknownDevice(name) if name == "foo"
+        ));
+        assert!(device_error("foo", tftp::Error::Timeout(3)).is_timeout());
+    }
+
+    #[test]
+    fn test_decode_listdev() {
+        // Length prefix, then a CSL with 8 byte payloads
+        let mut bytes = vec![0, 0, 8, 3];
+        bytes.extend_from_slice(b"foo");
+        bytes.extend_from_slice(&[0, 0, 0, 0x10, 0, 0, 0, 4]);
+        bytes.extend_from_slice(&[0, 0]);
+        let devices = decode_listdev(&bytes).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices["foo"], (0x10, 4));
+    }
+
+    #[test]
+    fn test_decode_listdev_wrong_payload() {
+        let mut bytes = vec![0, 0];
+        bytes.extend_from_slice(&crate::csl::tests::example());
+        assert!(matches!(
+            decode_listdev(&bytes),
+            Err(Error::Length {
+                expected: 8,
+                received: 1
+            })
+        ));
+    }
+
+    /// A `/listdev` response with a couple of devices
+    fn listdev_response() -> Vec<u8> {
+        let entries = [
+            ("adc", [0, 0, 0x10, 0, 0, 0, 0x01, 0]),
+            ("sys_board_id", [0, 0, 0, 0, 0, 0, 0, 4]),
+            ("sys_scratchpad", [0, 0, 0, 4, 0, 0, 0, 4]),
+        ];
+        let mut bytes = vec![0, 0];
+        bytes.extend_from_slice(&csl::encode(8, entries).unwrap());
+        bytes
+    }
+
+    #[test]
+    fn test_find_device() {
+        let bytes = listdev_response();
+        assert_eq!(find_device(&bytes, "adc").unwrap(), Some((0x1000, 0x100)));
+        assert_eq!(find_device(&bytes, "sys_scratchpad").unwrap(), Some((4, 4)));
+        assert_eq!(find_device(&bytes, "nope").unwrap(), None);
+    }
+
+    #[test]
+    fn test_device_at() {
+        let bytes = listdev_response();
+        assert_eq!(
+            device_at(&bytes, 0x10fc).unwrap(),
+            Some(("adc".to_owned(), 0xfc))
+        );
+        assert_eq!(
+            device_at(&bytes, 6).unwrap(),
+            Some(("sys_scratchpad".to_owned(), 2))
+        );
+        assert_eq!(device_at(&bytes, 0x1100).unwrap(), None);
+    }
+}
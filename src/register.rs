@@ -0,0 +1,497 @@
+//! Typed access to CASPER software registers (`sw_reg` blocks).
+//!
+//! A software register is a single 32-bit big-endian word on the bus. [`read_register`] and
+//! [`write_register`] move any [`Word`] type in and out of one, and [`read_fixed`] and
+//! [`write_fixed`] do the same for fixed-point values described by a [`Fixed`]. Offsets here are
+//! in bytes, as that's how register addresses are usually written down, and must land on a word.
+//!
+//! Registers that pack several fields into one word can be described with a [`Bitfields`], and
+//! then [`read_field`] and [`write_field`] get and set one field by name. Writing a field reads
+//! the whole word first so the other fields are left as they were.
+
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::{tftp::Config, Error};
+
+/// A value that fits in a single 32-bit register
+pub trait Word: Sized {
+    fn from_word(word: u32) -> Self;
+    fn into_word(self) -> u32;
+}
+
+impl Word for u32 {
+    fn from_word(word: u32) -> Self {
+        word
+    }
+
+    fn into_word(self) -> u32 {
+        self
+    }
+}
+
+impl Word for i32 {
+    fn from_word(word: u32) -> Self {
+        word as i32
+    }
+
+    fn into_word(self) -> u32 {
+        self as u32
+    }
+}
+
+impl Word for f32 {
+    fn from_word(word: u32) -> Self {
+        f32::from_bits(word)
+    }
+
+    fn into_word(self) -> u32 {
+        self.to_bits()
+    }
+}
+
+/// Anything nonzero reads as `true`, and `true` is written as 1
+impl Word for bool {
+    fn from_word(word: u32) -> Self {
+        word != 0
+    }
+
+    fn into_word(self) -> u32 {
+        self as u32
+    }
+}
+
+/// The layout of a fixed-point number in the low bits of a register, as the toolflow describes it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fixed {
+    bits: u32,
+    binary_point: u32,
+    signed: bool,
+}
+
+impl Fixed {
+    /// A `bits` wide number with `binary_point` of them after the point, in two's complement if
+    /// `signed`.
+    ///
+    /// # Panics
+    /// If `bits` isn't between 1 and 32, or `binary_point` is more than `bits`
+    pub fn new(bits: u32, binary_point: u32, signed: bool) -> Self {
+        assert!((1..=32).contains(&bits), "Registers are at most 32 bits");
+        assert!(
+            binary_point <= bits,
+            "The binary point must be within the number"
+        );
+        Fixed {
+            bits,
+            binary_point,
+            signed,
+        }
+    }
+
+    fn scale(&self) -> f64 {
+        2f64.powi(self.binary_point as i32)
+    }
+
+    /// The smallest and largest raw integers that fit
+    fn range(&self) -> (i64, i64) {
+        if self.signed {
+            (-(1 << (self.bits - 1)), (1 << (self.bits - 1)) - 1)
+        } else {
+            (0, (1 << self.bits) - 1)
+        }
+    }
+
+    /// The value in the low `bits` of `word`, ignoring the rest
+    pub fn decode(&self, word: u32) -> f64 {
+        let raw = word as u64 & ((1 << self.bits) - 1);
+        let raw = if self.signed && raw >> (self.bits - 1) == 1 {
+            raw as i64 - (1 << self.bits)
+        } else {
+            raw as i64
+        };
+        raw as f64 / self.scale()
+    }
+
+    /// `value` rounded to the nearest representable number, or an error if it doesn't fit
+    pub fn encode(&self, value: f64) -> Result<u32, Error> {
+        let raw = (value * self.scale()).round();
+        let (min, max) = self.range();
+        if !(min as f64..=max as f64).contains(&raw) {
+            return Err(Error::FixedRange {
+                value,
+                min: min as f64 / self.scale(),
+                max: max as f64 / self.scale(),
+            });
+        }
+        Ok((raw as i64 as u64 & ((1 << self.bits) - 1)) as u32)
+    }
+}
+
+/// One field of a [`Bitfields`] register
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Field {
+    pub name: String,
+    /// The bit the field starts at, counting from the least significant
+    pub offset: u32,
+    pub width: u32,
+    /// Whether the field is in two's complement
+    pub signed: bool,
+}
+
+impl Field {
+    fn mask(&self) -> u32 {
+        (((1u64 << self.width) - 1) as u32) << self.offset
+    }
+
+    /// The smallest and largest values that fit
+    fn range(&self) -> (i64, i64) {
+        if self.signed {
+            (-(1 << (self.width - 1)), (1 << (self.width - 1)) - 1)
+        } else {
+            (0, (1 << self.width) - 1)
+        }
+    }
+
+    fn get(&self, word: u32) -> i64 {
+        let raw = ((word & self.mask()) >> self.offset) as i64;
+        if self.signed && raw >> (self.width - 1) == 1 {
+            raw - (1 << self.width)
+        } else {
+            raw
+        }
+    }
+
+    fn set(&self, word: u32, value: i64) -> Result<u32, Error> {
+        let (min, max) = self.range();
+        if !(min..=max).contains(&value) {
+            return Err(Error::FieldRange {
+                field: self.name.clone(),
+                value,
+                min,
+                max,
+            });
+        }
+        let raw = ((value as u64) << self.offset) as u32 & self.mask();
+        Ok(word & !self.mask() | raw)
+    }
+}
+
+/// The layout of a register made up of several named fields
+///
+/// ```
+/// use tapcp::register::Bitfields;
+///
+/// let control = Bitfields::new()
+///     .field("rst", 0, 1, false)
+///     .field("enable", 1, 1, false)
+///     .field("shift", 4, 4, true);
+/// let word = control.set(0x1, "shift", -2).unwrap();
+/// assert_eq!(word, 0xE1);
+/// assert_eq!(control.get(word, "rst").unwrap(), 1);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfields {
+    fields: Vec<Field>,
+}
+
+impl Bitfields {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a field `width` bits wide starting at bit `offset`
+    ///
+    /// # Panics
+    /// If the field doesn't fit in 32 bits, or overlaps or shares a name with one already added
+    pub fn field(mut self, name: &str, offset: u32, width: u32, signed: bool) -> Self {
+        assert!(
+            width >= 1 && offset + width <= 32,
+            "Field {} doesn't fit in a register",
+            name
+        );
+        let field = Field {
+            name: name.to_owned(),
+            offset,
+            width,
+            signed,
+        };
+        for other in &self.fields {
+            assert!(other.name != name, "Field {} is defined twice", name);
+            assert!(
+                other.mask() & field.mask() == 0,
+                "Field {} overlaps {}",
+                name,
+                other.name
+            );
+        }
+        self.fields.push(field);
+        self
+    }
+
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    fn find(&self, name: &str) -> Result<&Field, Error> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .ok_or_else(|| Error::UnknownField(name.to_owned()))
+    }
+
+    /// The value of the field `name` in `word`
+    pub fn get(&self, word: u32, name: &str) -> Result<i64, Error> {
+        Ok(self.find(name)?.get(word))
+    }
+
+    /// `word` with the field `name` set to `value` and every other bit left alone
+    pub fn set(&self, word: u32, name: &str, value: i64) -> Result<u32, Error> {
+        self.find(name)?.set(word, value)
+    }
+}
+
+fn word_offset(byte_offset: usize) -> Result<usize, Error> {
+    if byte_offset % 4 != 0 {
+        return Err(Error::Unaligned {
+            offset: byte_offset,
+        });
+    }
+    Ok(byte_offset / 4)
+}
+
+/// Read the raw register `byte_offset` bytes into `device`
+fn read_word(
+    device: &str,
+    byte_offset: usize,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<u32, Error> {
+    let bytes = crate::read_device(device, word_offset(byte_offset)?, 1, socket, addr, config)?;
+    Ok(u32::from_be_bytes(
+        bytes[..4]
+            .try_into()
+            .expect("read_device checked the length"),
+    ))
+}
+
+/// Read the register `byte_offset` bytes into `device` as a `T`
+pub fn read_register<T: Word>(
+    device: &str,
+    byte_offset: usize,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<T, Error> {
+    read_word(device, byte_offset, socket, addr, config).map(T::from_word)
+}
+
+/// Write `value` to the register `byte_offset` bytes into `device`
+pub fn write_register<T: Word>(
+    device: &str,
+    byte_offset: usize,
+    value: T,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let bytes = value.into_word().to_be_bytes();
+    crate::write_device(
+        device,
+        word_offset(byte_offset)?,
+        &bytes,
+        socket,
+        addr,
+        config,
+    )
+}
+
+/// Read the register `byte_offset` bytes into `device` as a fixed-point number laid out as `format`
+pub fn read_fixed(
+    device: &str,
+    byte_offset: usize,
+    format: Fixed,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<f64, Error> {
+    read_word(device, byte_offset, socket, addr, config).map(|word| format.decode(word))
+}
+
+/// Write `value` as a fixed-point number laid out as `format` to the register `byte_offset`
+/// bytes into `device`
+pub fn write_fixed(
+    device: &str,
+    byte_offset: usize,
+    value: f64,
+    format: Fixed,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let word = format.encode(value)?;
+    write_register(device, byte_offset, word, socket, addr, config)
+}
+
+/// Read the field `name` of the register `byte_offset` bytes into `device`, laid out as `layout`
+pub fn read_field(
+    device: &str,
+    byte_offset: usize,
+    layout: &Bitfields,
+    name: &str,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<i64, Error> {
+    let field = layout.find(name)?;
+    read_word(device, byte_offset, socket, addr, config).map(|word| field.get(word))
+}
+
+/// Set the field `name` of the register `byte_offset` bytes into `device`, laid out as `layout`, to
+/// `value`. This reads the register first and writes it back with only that field changed.
+#[allow(clippy::too_many_arguments)]
+pub fn write_field(
+    device: &str,
+    byte_offset: usize,
+    layout: &Bitfields,
+    name: &str,
+    value: i64,
+    socket: &mut UdpSocket,
+    addr: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let field = layout.find(name)?;
+    let word = read_word(device, byte_offset, socket, addr, config)?;
+    write_register(
+        device,
+        byte_offset,
+        field.set(word, value)?,
+        socket,
+        addr,
+        config,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tftp::{tests::next_packet, Payload};
+    use std::thread;
+
+    #[test]
+    fn test_words() {
+        assert_eq!((-2i32).into_word(), 0xFFFF_FFFE);
+        assert_eq!(i32::from_word(0xFFFF_FFFE), -2);
+        assert_eq!(f32::from_word(1.5f32.into_word()), 1.5);
+        assert!(bool::from_word(0x100));
+        assert_eq!(true.into_word(), 1);
+    }
+
+    #[test]
+    fn test_fixed_unsigned() {
+        let format = Fixed::new(16, 8, false);
+        assert_eq!(format.encode(1.5).unwrap(), 0x0180);
+        assert_eq!(format.decode(0x0180), 1.5);
+        // Bits above the number are ignored
+        assert_eq!(format.decode(0xFFFF_0180), 1.5);
+        assert!(matches!(format.encode(-1.0), Err(Error::FixedRange { .. })));
+        assert!(format.encode(256.0).is_err());
+    }
+
+    #[test]
+    fn test_fixed_signed() {
+        let format = Fixed::new(8, 4, true);
+        assert_eq!(format.encode(-1.0).unwrap(), 0xF0);
+        assert_eq!(format.decode(0xF0), -1.0);
+        assert_eq!(format.decode(0x7F), 7.9375);
+        assert_eq!(format.encode(-8.0).unwrap(), 0x80);
+        assert!(format.encode(8.0).is_err());
+        // Full width
+        let format = Fixed::new(32, 0, true);
+        assert_eq!(format.decode(0x8000_0000), i32::MIN as f64);
+    }
+
+    #[test]
+    fn test_unaligned() {
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = "127.0.0.1:9".parse().unwrap();
+        let result = read_register::<u32>("gain", 2, &mut socket, addr, &Config::default());
+        assert!(matches!(result, Err(Error::Unaligned { offset: 2 })));
+    }
+
+    fn control() -> Bitfields {
+        Bitfields::new()
+            .field("rst", 0, 1, false)
+            .field("enable", 1, 1, false)
+            .field("gain", 8, 8, true)
+    }
+
+    #[test]
+    fn test_bitfields() {
+        let control = control();
+        let word = control.set(0xFFFF_0001, "gain", -1).unwrap();
+        assert_eq!(word, 0xFFFF_FF01);
+        assert_eq!(control.get(word, "gain").unwrap(), -1);
+        assert_eq!(control.get(word, "enable").unwrap(), 0);
+        assert_eq!(control.set(word, "rst", 0).unwrap(), 0xFFFF_FF00);
+        assert!(matches!(
+            control.set(word, "gain", 128),
+            Err(Error::FieldRange {
+                min: -128,
+                max: 127,
+                ..
+            })
+        ));
+        assert!(matches!(
+            control.get(word, "nope"),
+            Err(Error::UnknownField(name)) if name == "nope"
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn test_overlapping_fields() {
+        control().field("mode", 15, 2, false);
+    }
+
+    #[test]
+    fn test_write_field() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            // The read half of the read-modify-write
+            let (request, from) = next_packet(&server);
+            assert!(
+                matches!(request, Payload::Read { filename, .. } if filename == "/dev/ctrl.1.1")
+            );
+            let data = Payload::Data {
+                block: 1,
+                data: 0x0000_AB02u32.to_be_bytes().to_vec(),
+            };
+            server.send_to(&data.pack(), from).unwrap();
+            next_packet(&server);
+            // Then the write, which should only have touched rst
+            let (request, from) = next_packet(&server);
+            assert!(
+                matches!(request, Payload::Write { filename, .. } if filename == "/dev/ctrl.1")
+            );
+            server
+                .send_to(&Payload::Ack { block: 0 }.pack(), from)
+                .unwrap();
+            let (data, from) = next_packet(&server);
+            server
+                .send_to(&Payload::Ack { block: 1 }.pack(), from)
+                .unwrap();
+            data
+        });
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            timeout: std::time::Duration::from_millis(100),
+            ..Default::default()
+        };
+        write_field("ctrl", 4, &control(), "rst", 1, &mut socket, addr, &config).unwrap();
+        let written = handle.join().unwrap();
+        assert!(
+            matches!(written, Payload::Data { data, .. } if data == 0x0000_AB03u32.to_be_bytes())
+        );
+    }
+}
@@ -0,0 +1,512 @@
+//! A simulated TAPCP board on localhost, for testing without hardware.
+//!
+//! [`Simulator`] runs a [`Server`] on a background thread that answers the same requests a real
+//! board does: `/help`, `/temp`, `/listdev`, `/dev/NAME.OFFSET.N`, `/flash.OFFSET.N` and
+//! `/progdev`, all against the in-memory [`Board`]. Each transfer gets its own socket (and so its
+//! own TID), just like on the real thing.
+//!
+//! ```
+//! use tapcp::sim::{Board, Simulator};
+//!
+//! let sim = Simulator::start(Board::new().with_device("gain", 0x1000, 4)).unwrap();
+//! let tapcp = sim.tapcp().unwrap();
+//! tapcp.write_device("gain", 0, &[0, 0, 0, 42]).unwrap();
+//! assert_eq!(sim.board().devices["gain"].bytes, [0, 0, 0, 42]);
+//! ```
+//!
+//! Put a [`FaultProxy`] in front of it to see how the client copes with a bad network.
+
+mod fault;
+
+pub use fault::{FaultProxy, Faults, Stats};
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    csl,
+    flash::{FLASH_SIZE, SECTOR_SIZE},
+    tftp::{Backend, Config, ErrorCode, Reply, Server},
+    Error, Tapcp,
+};
+
+/// How long the simulator waits for a packet before resending its last one
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How many times the simulator resends before dropping a transfer
+const RETRIES: usize = 5;
+
+/// The memory behind one gateware device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Memory {
+    /// Byte address on the bus, as reported by `/listdev`
+    pub address: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Everything the simulated board knows
+#[derive(Debug, Clone)]
+pub struct Board {
+    /// What `/temp` reads, in Celsius
+    pub temp: f32,
+    /// What `/help` reads
+    pub help: String,
+    pub devices: BTreeMap<String, Memory>,
+    /// The whole flash, starting erased
+    pub flash: Vec<u8>,
+    /// The byte address in flash the board was last told to boot from with `/progdev`
+    pub booted: Option<u32>,
+    /// The words waiting in each FIFO device, by name
+    pub fifos: BTreeMap<String, VecDeque<u8>>,
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Board {
+    /// A board with the `sys_board_id` and `sys_scratchpad` devices every design has
+    pub fn new() -> Self {
+        Board {
+            temp: 42.5,
+            help: "Available commands:\n/help\n/temp\n/listdev\n/dev/\n/flash\n/progdev\n"
+                .to_owned(),
+            devices: BTreeMap::new(),
+            flash: vec![0xFF; FLASH_SIZE],
+            booted: None,
+            fifos: BTreeMap::new(),
+        }
+        .with_device("sys_board_id", 0, 4)
+        .with_device("sys_scratchpad", 4, 4)
+    }
+
+    /// Add a zeroed device `length` bytes long at bus address `address`
+    pub fn with_device(mut self, name: &str, address: u32, length: u32) -> Self {
+        self.devices.insert(
+            name.to_owned(),
+            Memory {
+                address,
+                bytes: vec![0; length as usize],
+            },
+        );
+        self
+    }
+
+    /// Add a FIFO device at bus address `address`, with a `NAME_level` register after it holding
+    /// how many words are waiting. Reading the FIFO pops words off the front whatever the offset,
+    /// and reads zeros once it's empty.
+    pub fn with_fifo(mut self, name: &str, address: u32) -> Self {
+        self.fifos.insert(name.to_owned(), VecDeque::new());
+        self.with_device(name, address, 4)
+            .with_device(&format!("{}_level", name), address + 4, 4)
+    }
+
+    /// Queue up `data`, a whole number of words, on the FIFO `name`
+    pub fn push_fifo(&mut self, name: &str, data: &[u8]) {
+        self.fifos
+            .get_mut(name)
+            .expect("Not a FIFO device")
+            .extend(data);
+        self.update_level(name);
+    }
+
+    /// Pop `n` bytes off the FIFO `name`, padding with zeros if it runs dry
+    fn pop_fifo(&mut self, name: &str, n: usize) -> Vec<u8> {
+        let fifo = self.fifos.get_mut(name).expect("Not a FIFO device");
+        let mut bytes: Vec<u8> = fifo.drain(..n.min(fifo.len())).collect();
+        bytes.resize(n, 0);
+        self.update_level(name);
+        bytes
+    }
+
+    fn update_level(&mut self, name: &str) {
+        let words = (self.fifos[name].len() / 4) as u32;
+        if let Some(level) = self.devices.get_mut(&format!("{}_level", name)) {
+            level.bytes = words.to_be_bytes().to_vec();
+        }
+    }
+
+    /// The response to `/listdev`, a length prefix and then a CSL of (addr,length)
+    fn listdev(&self) -> Vec<u8> {
+        let entries = self.devices.iter().map(|(name, memory)| {
+            let mut payload = memory.address.to_be_bytes().to_vec();
+            payload.extend_from_slice(&(memory.bytes.len() as u32).to_be_bytes());
+            (name, payload)
+        });
+        let csl = csl::encode(8, entries).expect("Device names are valid keys");
+        let mut bytes = (csl.len() as u16).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&csl);
+        bytes
+    }
+
+    /// The memory a device or flash `target` refers to
+    fn memory(&mut self, target: &Target) -> Result<&mut Vec<u8>, Reply> {
+        match target {
+            Target::Device { name, .. } => self
+                .devices
+                .get_mut(name)
+                .map(|memory| &mut memory.bytes)
+                .ok_or_else(|| not_found(name)),
+            Target::Flash { .. } => Ok(&mut self.flash),
+            _ => Err(illegal("Not a memory")),
+        }
+    }
+
+    /// Answer a read request
+    fn read(&mut self, target: &Target) -> Result<Vec<u8>, Reply> {
+        match target {
+            Target::Help => Ok(self.help.clone().into_bytes()),
+            Target::Temp => Ok(self.temp.to_be_bytes().to_vec()),
+            Target::Listdev => Ok(self.listdev()),
+            Target::Device { name, n, .. } if self.fifos.contains_key(name) => {
+                Ok(self.pop_fifo(name, n.unwrap_or(4)))
+            }
+            Target::Device { offset, n, .. } | Target::Flash { offset, n } => {
+                let (offset, n) = (*offset, *n);
+                let memory = self.memory(target)?;
+                let end = n.map_or(memory.len(), |n| offset + n);
+                memory
+                    .get(offset..end)
+                    .map(<[u8]>::to_vec)
+                    .ok_or_else(|| illegal("Out of range"))
+            }
+            Target::Progdev => Err(illegal("Can't read /progdev")),
+        }
+    }
+
+    /// Check a write request can go ahead before taking any data
+    fn check_write(&mut self, target: &Target) -> Result<(), Reply> {
+        match target {
+            Target::Device { .. } | Target::Flash { .. } => self.memory(target).map(|_| ()),
+            Target::Progdev => Ok(()),
+            _ => Err(illegal("Read only")),
+        }
+    }
+
+    /// Carry out a write request with all of its `data`
+    fn write(&mut self, target: &Target, data: &[u8]) -> Result<(), Reply> {
+        match target {
+            Target::Device { offset, .. } => {
+                let offset = *offset;
+                let memory = self.memory(target)?;
+                memory
+                    .get_mut(offset..offset + data.len())
+                    .ok_or_else(|| illegal("Out of range"))?
+                    .copy_from_slice(data);
+                Ok(())
+            }
+            Target::Flash { offset, .. } => {
+                let offset = *offset;
+                if offset + data.len() > self.flash.len() {
+                    return Err(illegal("Out of range"));
+                }
+                // Like the firmware, writing from the start of a sector erases it first. After
+                // that, programming can only clear bits.
+                for (i, &byte) in data.iter().enumerate() {
+                    let address = offset + i;
+                    if address % SECTOR_SIZE == 0 {
+                        let end = (address + SECTOR_SIZE).min(self.flash.len());
+                        self.flash[address..end].fill(0xFF);
+                    }
+                    self.flash[address] &= byte;
+                }
+                Ok(())
+            }
+            Target::Progdev => {
+                let word = data.get(..4).ok_or_else(|| illegal("Too short"))?;
+                let word = u32::from_be_bytes(word.try_into().expect("Sliced to 4 bytes"));
+                self.booted = Some(word << 8);
+                Ok(())
+            }
+            _ => Err(illegal("Read only")),
+        }
+    }
+}
+
+fn not_found(name: &str) -> Reply {
+    (ErrorCode::NotFound, name.to_owned())
+}
+
+fn illegal(message: &str) -> Reply {
+    (ErrorCode::IllegalOp, message.to_owned())
+}
+
+/// What a request filename refers to. Offsets and lengths are in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Help,
+    Temp,
+    Listdev,
+    Device {
+        name: String,
+        offset: usize,
+        n: Option<usize>,
+    },
+    Flash {
+        offset: usize,
+        n: Option<usize>,
+    },
+    Progdev,
+}
+
+/// Parse the `.WORD_OFFSET[.NWORDS]` hex suffix of a filename into a byte offset and length
+fn offset_and_length<'a>(
+    mut parts: impl Iterator<Item = &'a str>,
+) -> Result<(usize, Option<usize>), Reply> {
+    let mut word = || {
+        parts
+            .next()
+            .map(|part| usize::from_str_radix(part, 16).map_err(|_| illegal("Bad number")))
+            .transpose()
+    };
+    let offset = word()?.unwrap_or(0) * 4;
+    // Asking for 0 words means everything from the offset on
+    let n = word()?.filter(|&n| n != 0).map(|n| n * 4);
+    Ok((offset, n))
+}
+
+impl Target {
+    fn parse(filename: &str) -> Result<Self, Reply> {
+        Ok(match filename {
+            "/help" => Target::Help,
+            "/temp" => Target::Temp,
+            "/listdev" => Target::Listdev,
+            "/progdev" => Target::Progdev,
+            _ => {
+                if let Some(device) = filename.strip_prefix("/dev/") {
+                    let mut parts = device.split('.');
+                    let name = parts.next().unwrap_or_default().to_owned();
+                    let (offset, n) = offset_and_length(parts)?;
+                    Target::Device { name, offset, n }
+                } else if let Some(flash) = filename.strip_prefix("/flash.") {
+                    let (offset, n) = offset_and_length(flash.split('.'))?;
+                    Target::Flash { offset, n }
+                } else {
+                    return Err(not_found(filename));
+                }
+            }
+        })
+    }
+}
+
+/// The board as a TFTP backend, shared with the [`Simulator`] that owns it
+struct Shared(Arc<Mutex<Board>>);
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Board> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Backend for Shared {
+    fn read(&self, filename: &str) -> Result<Vec<u8>, Reply> {
+        self.lock().read(&Target::parse(filename)?)
+    }
+
+    fn check_write(&self, filename: &str) -> Result<(), Reply> {
+        self.lock().check_write(&Target::parse(filename)?)
+    }
+
+    fn write(&self, filename: &str, data: &[u8]) -> Result<(), Reply> {
+        self.lock().write(&Target::parse(filename)?, data)
+    }
+}
+
+/// A simulated board serving TAPCP on localhost. It stops when dropped.
+#[derive(Debug)]
+pub struct Simulator {
+    addr: SocketAddr,
+    board: Arc<Mutex<Board>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Simulator {
+    /// Start serving `board` on a free port on localhost
+    pub fn start(board: Board) -> io::Result<Self> {
+        let board = Arc::new(Mutex::new(board));
+        let config = Config {
+            timeout: TIMEOUT,
+            retries: RETRIES,
+            ..Default::default()
+        };
+        let server = Server::bind("127.0.0.1:0", Shared(board.clone()), config)?;
+        let addr = server.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let _ = server.serve(&stop);
+            })
+        };
+        Ok(Simulator {
+            addr,
+            board,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The address requests should be sent to, in place of a board's port 69
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The board's current state, which can be inspected or changed between requests
+    pub fn board(&self) -> MutexGuard<'_, Board> {
+        self.board
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// A client connected to this simulator
+    pub fn tapcp(&self) -> Result<Tapcp, Error> {
+        Tapcp::builder(&self.addr.ip().to_string())
+            .port(self.addr.port())
+            .build()
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        flash::{self, USER_FLASH_ADDR},
+        fpg::Fpg,
+    };
+    use std::net::UdpSocket;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            Target::parse("/dev/adc.10.2").unwrap(),
+            Target::Device {
+                name: "adc".to_owned(),
+                offset: 0x40,
+                n: Some(8)
+            }
+        );
+        assert_eq!(
+            Target::parse("/flash.200").unwrap(),
+            Target::Flash {
+                offset: 0x800,
+                n: None
+            }
+        );
+        assert!(Target::parse("/nope").is_err());
+    }
+
+    #[test]
+    fn test_board_requests() {
+        let sim = Simulator::start(Board::new().with_device("adc", 0x1000, 0x400)).unwrap();
+        let tapcp = sim.tapcp().unwrap();
+        assert_eq!(tapcp.temp().unwrap(), 42.5);
+        assert!(tapcp.help().unwrap().contains("/listdev"));
+        let devices = tapcp.listdev().unwrap();
+        assert_eq!(devices["adc"], (0x1000, 0x400));
+        assert_eq!(devices["sys_scratchpad"], (4, 4));
+        // Bigger than a single block, at an offset
+        let data: Vec<u8> = (0..0x300).map(|i| i as u8).collect();
+        tapcp.write_device("adc", 0x10, &data).unwrap();
+        assert_eq!(tapcp.read_device("adc", 0x10, 0xC0).unwrap(), data);
+        assert!(matches!(
+            tapcp.read_device("nope", 0, 1),
+            Err(Error::UnknownDevice(_))
+        ));
+    }
+
+    #[test]
+    fn test_program() {
+        let sim = Simulator::start(Board::new()).unwrap();
+        let tapcp = sim.tapcp().unwrap();
+        let fpg = Fpg::parse(b"?register\tgain\t0x10\t0x4\n?quit\n\x01\x02\x03").unwrap();
+        tapcp.program_fpg(&fpg, |_| {}).unwrap();
+        assert_eq!(sim.board().booted, Some(USER_FLASH_ADDR));
+        let start = USER_FLASH_ADDR as usize;
+        assert_eq!(sim.board().flash[start..start + 4], [1, 2, 3, 0xFF]);
+        let stored = tapcp.read_fpg().unwrap().unwrap();
+        assert_eq!(stored.devices, fpg.devices);
+        // Partial writes keep the rest of the sector
+        tapcp.write_flash(start / 4 + 1, &[9; 4], true).unwrap();
+        assert_eq!(
+            sim.board().flash[start..start + 8],
+            [1, 2, 3, 0xFF, 9, 9, 9, 9]
+        );
+        assert!(matches!(
+            flash::write_flash(
+                0,
+                &[0; 4],
+                false,
+                &mut UdpSocket::bind("127.0.0.1:0").unwrap(),
+                sim.addr(),
+                tapcp.config()
+            ),
+            Err(Error::GoldenImage { .. })
+        ));
+    }
+
+    #[test]
+    fn test_write_past_end_of_flash() {
+        let sim = Simulator::start(Board::new()).unwrap();
+        let tapcp = sim.tapcp().unwrap();
+        // The last word is fine, but one more would wrap round to the golden image
+        let last = FLASH_SIZE / 4 - 1;
+        assert!(matches!(
+            tapcp.write_flash(last, &[0; 8], true),
+            Err(Error::PastEndOfFlash { address, length: 8 }) if address == FLASH_SIZE - 4
+        ));
+        assert!(sim.board().flash.iter().all(|&b| b == 0xFF));
+        tapcp.write_flash(last, &[0; 4], true).unwrap();
+        assert_eq!(sim.board().flash[FLASH_SIZE - 4..], [0; 4]);
+    }
+
+    #[test]
+    fn test_program_rejects_bad_bitstreams() {
+        let sim = Simulator::start(Board::new()).unwrap();
+        let tapcp = sim.tapcp().unwrap();
+        assert!(matches!(
+            tapcp.program(&[], |_| {}),
+            Err(Error::EmptyBitstream)
+        ));
+        let max = FLASH_SIZE - USER_FLASH_ADDR as usize;
+        assert!(matches!(
+            tapcp.program(&vec![0; max + 1], |_| {}),
+            Err(Error::ImageTooLarge { length, .. }) if length == max + 1
+        ));
+        // A bitstream that fills the region leaves no room for the header
+        let fpg = Fpg::parse(b"?quit\n").unwrap();
+        let fpg = Fpg {
+            bitstream: vec![0; max],
+            ..fpg
+        };
+        assert!(matches!(
+            tapcp.program_fpg(&fpg, |_| {}),
+            Err(Error::ImageTooLarge { .. })
+        ));
+        // None of which touched flash
+        assert!(sim.board().flash.iter().all(|&b| b == 0xFF));
+        assert_eq!(sim.board().booted, None);
+    }
+}
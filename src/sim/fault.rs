@@ -0,0 +1,491 @@
+//! A UDP proxy that gets in the way of TFTP on purpose.
+//!
+//! [`FaultProxy`] sits between a client and a server (usually a [`super::Simulator`]) and passes
+//! datagrams both ways, dropping, duplicating, delaying, reordering or corrupting them as the
+//! [`Faults`] say. Decisions come from a seeded generator, so a given seed makes the same choices
+//! for the same sequence of datagrams. [`FaultProxy::fail_next`] answers the next datagram from the
+//! client with a TFTP error instead of passing it on.
+//!
+//! Just like the server behind it, the proxy answers each transfer from its own port, so the
+//! client sees one TID per server TID.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::tftp::{ErrorCode, Payload, MAX_DATA};
+
+/// How long the proxy sleeps when there's nothing to do
+const IDLE: Duration = Duration::from_millis(1);
+
+/// The longest a reordered datagram is held back waiting for another to overtake it
+const MAX_HOLD: Duration = Duration::from_millis(100);
+
+/// How likely each fault is, from 0 (never) to 1 (always), for every datagram in either direction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Faults {
+    pub drop: f64,
+    pub duplicate: f64,
+    /// Hold the datagram back for up to `max_delay`
+    pub delay: f64,
+    pub max_delay: Duration,
+    /// Hold the datagram back until the next one going the same way has been sent
+    pub reorder: f64,
+    /// Mangle the opcode so the datagram can't be decoded
+    pub corrupt: f64,
+}
+
+impl Default for Faults {
+    /// No faults at all
+    fn default() -> Self {
+        Faults {
+            drop: 0.0,
+            duplicate: 0.0,
+            delay: 0.0,
+            max_delay: Duration::from_millis(50),
+            reorder: 0.0,
+            corrupt: 0.0,
+        }
+    }
+}
+
+/// How many of each fault the proxy has injected so far
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub forwarded: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub delayed: usize,
+    pub reordered: usize,
+    pub corrupted: usize,
+    pub errors: usize,
+}
+
+/// SplitMix64, which is plenty random for picking faults and keeps us free of dependencies
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+enum Direction {
+    ToServer,
+    ToClient,
+}
+
+/// A datagram on its way through the proxy
+#[derive(Debug, Clone)]
+struct Datagram {
+    direction: Direction,
+    /// The socket to send it from
+    via: Arc<UdpSocket>,
+    to: SocketAddr,
+    bytes: Vec<u8>,
+    /// Where the client is and which of our sockets it sent this on, for answering with errors
+    client: SocketAddr,
+    client_via: Arc<UdpSocket>,
+}
+
+/// The sockets we use for one client
+#[derive(Debug)]
+struct Link {
+    /// What we talk to the server from
+    back: Arc<UdpSocket>,
+    /// What we talk to the client from, one for each server TID
+    fronts: HashMap<SocketAddr, Arc<UdpSocket>>,
+}
+
+fn bind() -> io::Result<Arc<UdpSocket>> {
+    let socket = UdpSocket::bind("127.0.0.1:0")?;
+    socket.set_nonblocking(true)?;
+    Ok(Arc::new(socket))
+}
+
+struct Proxy {
+    front: Arc<UdpSocket>,
+    server: SocketAddr,
+    faults: Faults,
+    rng: Rng,
+    links: HashMap<SocketAddr, Link>,
+    delayed: Vec<(Instant, Datagram)>,
+    held: HashMap<Direction, (Instant, Datagram)>,
+    stats: Arc<Mutex<Stats>>,
+    fail: Arc<Mutex<Option<(ErrorCode, String)>>>,
+}
+
+impl Proxy {
+    fn stats(&self) -> MutexGuard<'_, Stats> {
+        self.stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn link(&mut self, client: SocketAddr) -> io::Result<&mut Link> {
+        match self.links.entry(client) {
+            Entry::Occupied(link) => Ok(link.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(Link {
+                back: bind()?,
+                fronts: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Pick up whatever has arrived on any of our sockets, returning whether there was anything
+    fn poll(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 4 + MAX_DATA];
+        let mut incoming = vec![];
+        // New requests from clients
+        while let Ok((n, client)) = self.front.recv_from(&mut buf) {
+            let back = self.link(client)?.back.clone();
+            incoming.push(Datagram {
+                direction: Direction::ToServer,
+                via: back,
+                to: self.server,
+                bytes: buf[..n].to_vec(),
+                client,
+                client_via: self.front.clone(),
+            });
+        }
+        let clients: Vec<_> = self.links.keys().copied().collect();
+        for client in clients {
+            // Replies from the server, which we pass on from the port standing in for its TID
+            let back = self.links[&client].back.clone();
+            while let Ok((n, tid)) = back.recv_from(&mut buf) {
+                let link = self.link(client)?;
+                let front = match link.fronts.get(&tid) {
+                    Some(front) => front.clone(),
+                    None => {
+                        let front = bind()?;
+                        link.fronts.insert(tid, front.clone());
+                        front
+                    }
+                };
+                incoming.push(Datagram {
+                    direction: Direction::ToClient,
+                    via: front.clone(),
+                    to: client,
+                    bytes: buf[..n].to_vec(),
+                    client,
+                    client_via: front,
+                });
+            }
+            // The rest of each transfer from the client
+            for (&tid, front) in &self.links[&client].fronts {
+                while let Ok((n, from)) = front.recv_from(&mut buf) {
+                    incoming.push(Datagram {
+                        direction: Direction::ToServer,
+                        via: back.clone(),
+                        to: tid,
+                        bytes: buf[..n].to_vec(),
+                        client: from,
+                        client_via: front.clone(),
+                    });
+                }
+            }
+        }
+        let busy = !incoming.is_empty();
+        for datagram in incoming {
+            self.inject(datagram)?;
+        }
+        Ok(busy)
+    }
+
+    /// Decide what happens to `datagram`
+    fn inject(&mut self, mut datagram: Datagram) -> io::Result<()> {
+        if datagram.direction == Direction::ToServer {
+            let fail = self
+                .fail
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .take();
+            if let Some((error_code, error_msg)) = fail {
+                self.stats().errors += 1;
+                let error = Payload::Error {
+                    error_code,
+                    error_msg,
+                };
+                datagram
+                    .client_via
+                    .send_to(&error.pack(), datagram.client)?;
+                return Ok(());
+            }
+        }
+        let faults = self.faults;
+        if self.rng.chance(faults.drop) {
+            self.stats().dropped += 1;
+            return Ok(());
+        }
+        if self.rng.chance(faults.corrupt) {
+            self.stats().corrupted += 1;
+            // Opcodes only go up to 6 (OACK), so anything past that can't be decoded
+            let opcode = 7 + (self.rng.next_u64() % 0xFF00) as u16;
+            if datagram.bytes.len() >= 2 {
+                datagram.bytes[..2].copy_from_slice(&opcode.to_be_bytes());
+            }
+        }
+        if self.rng.chance(faults.duplicate) {
+            self.stats().duplicated += 1;
+            self.send(datagram.clone())?;
+        }
+        if self.rng.chance(faults.delay) {
+            self.stats().delayed += 1;
+            let delay = faults.max_delay.mul_f64(self.rng.next_f64());
+            self.delayed.push((Instant::now() + delay, datagram));
+            return Ok(());
+        }
+        if !self.held.contains_key(&datagram.direction) && self.rng.chance(faults.reorder) {
+            self.stats().reordered += 1;
+            self.held
+                .insert(datagram.direction, (Instant::now() + MAX_HOLD, datagram));
+            return Ok(());
+        }
+        let direction = datagram.direction;
+        self.send(datagram)?;
+        // Anything held back for reordering goes out after this one
+        if let Some((_, held)) = self.held.remove(&direction) {
+            self.send(held)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, datagram: Datagram) -> io::Result<()> {
+        self.stats().forwarded += 1;
+        datagram
+            .via
+            .send_to(&datagram.bytes, datagram.to)
+            .map(|_| ())
+    }
+
+    /// Send anything whose delay or hold has run out
+    fn release(&mut self) -> io::Result<()> {
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = self
+            .delayed
+            .drain(..)
+            .partition(|(release, _)| *release <= now);
+        self.delayed = waiting;
+        for (_, datagram) in due {
+            self.send(datagram)?;
+        }
+        let expired: Vec<_> = self
+            .held
+            .iter()
+            .filter(|(_, (release, _))| *release <= now)
+            .map(|(direction, _)| *direction)
+            .collect();
+        for direction in expired {
+            let (_, datagram) = self.held.remove(&direction).expect("Just found");
+            self.send(datagram)?;
+        }
+        Ok(())
+    }
+
+    fn run(mut self, stop: Arc<AtomicBool>) {
+        while !stop.load(Ordering::Relaxed) {
+            // A send can fail if the other end has gone away, which is its problem, not ours
+            let busy = self.poll().unwrap_or(true);
+            let _ = self.release();
+            if !busy {
+                thread::sleep(IDLE);
+            }
+        }
+    }
+}
+
+/// A proxy in front of a TFTP server that injects faults. It stops when dropped.
+#[derive(Debug)]
+pub struct FaultProxy {
+    addr: SocketAddr,
+    stats: Arc<Mutex<Stats>>,
+    fail: Arc<Mutex<Option<(ErrorCode, String)>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FaultProxy {
+    /// Start proxying to the TFTP server at `server`, with faults picked by a generator seeded
+    /// with `seed`
+    pub fn start(server: SocketAddr, faults: Faults, seed: u64) -> io::Result<Self> {
+        let front = bind()?;
+        let addr = front.local_addr()?;
+        let stats = Arc::new(Mutex::new(Stats::default()));
+        let fail = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        let proxy = Proxy {
+            front,
+            server,
+            faults,
+            rng: Rng(seed),
+            links: HashMap::new(),
+            delayed: vec![],
+            held: HashMap::new(),
+            stats: stats.clone(),
+            fail: fail.clone(),
+        };
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || proxy.run(stop))
+        };
+        Ok(FaultProxy {
+            addr,
+            stats,
+            fail,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// The address clients should send requests to instead of the server's
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Answer the next datagram from a client with this error instead of passing it on
+    pub fn fail_next(&self, error_code: ErrorCode, error_msg: &str) {
+        *self
+            .fail
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+            Some((error_code, error_msg.to_owned()));
+    }
+
+    pub fn stats(&self) -> Stats {
+        *self
+            .stats
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for FaultProxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{Board, Simulator},
+        tftp, Error, Tapcp,
+    };
+
+    /// A simulator with a 4 KiB device, and a client going through a proxy in front of it
+    fn setup(faults: Faults, seed: u64) -> (Simulator, FaultProxy, Tapcp) {
+        let sim = Simulator::start(Board::new().with_device("bram", 0x1000, 0x1000)).unwrap();
+        let proxy = FaultProxy::start(sim.addr(), faults, seed).unwrap();
+        let tapcp = Tapcp::builder("127.0.0.1")
+            .port(proxy.addr().port())
+            .timeout(Duration::from_millis(20))
+            .retries(20)
+            .build()
+            .unwrap();
+        (sim, proxy, tapcp)
+    }
+
+    #[test]
+    fn test_rng_is_seeded() {
+        let mut a = Rng(7);
+        let mut b = Rng(7);
+        let a: Vec<_> = (0..8).map(|_| a.next_u64()).collect();
+        let b: Vec<_> = (0..8).map(|_| b.next_u64()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, (0..8).map(|_| Rng(8).next_u64()).collect::<Vec<_>>());
+        let mut rng = Rng(1);
+        assert!((0..1000)
+            .map(|_| rng.next_f64())
+            .all(|x| (0.0..1.0).contains(&x)));
+    }
+
+    #[test]
+    fn test_clean() {
+        let (_sim, proxy, tapcp) = setup(Faults::default(), 0);
+        assert_eq!(tapcp.temp().unwrap(), 42.5);
+        assert_eq!(tapcp.listdev().unwrap()["bram"], (0x1000, 0x1000));
+        let stats = proxy.stats();
+        assert!(stats.forwarded > 0);
+        assert_eq!(stats.dropped + stats.corrupted + stats.errors, 0);
+    }
+
+    #[test]
+    fn test_survives_faults() {
+        let faults = Faults {
+            drop: 0.1,
+            duplicate: 0.1,
+            delay: 0.1,
+            max_delay: Duration::from_millis(30),
+            reorder: 0.1,
+            corrupt: 0.05,
+        };
+        let (sim, _proxy, tapcp) = setup(faults, 0x5EED);
+        for round in 0..4u8 {
+            let data: Vec<u8> = (0..0x1000).map(|i| (i as u8) ^ round).collect();
+            tapcp.write_device("bram", 0, &data).unwrap();
+            assert_eq!(sim.board().devices["bram"].bytes, data);
+            assert_eq!(tapcp.read_device("bram", 0, 0x400).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_fail_next() {
+        let (_sim, proxy, tapcp) = setup(Faults::default(), 0);
+        proxy.fail_next(ErrorCode::Full, "No room");
+        assert!(matches!(
+            tapcp.write_device("bram", 0, &[0; 4]),
+            Err(Error::Tftp(tftp::Error::ErrorResponse(ErrorCode::Full, msg))) if msg == "No room"
+        ));
+        proxy.fail_next(ErrorCode::NotFound, "");
+        assert!(matches!(
+            tapcp.read_device("bram", 0, 1),
+            Err(Error::UnknownDevice(_))
+        ));
+        // Back to normal
+        assert!(tapcp.read_device("bram", 0, 1).is_ok());
+        assert_eq!(proxy.stats().errors, 2);
+    }
+
+    #[test]
+    fn test_everything_dropped() {
+        let faults = Faults {
+            drop: 1.0,
+            ..Default::default()
+        };
+        let (sim, _proxy, tapcp) = setup(faults, 0);
+        assert!(tapcp.temp().unwrap_err().is_timeout());
+        // The board never heard us, so it hasn't rebooted
+        assert!(matches!(
+            tapcp.reboot_golden(),
+            Err(Error::Tftp(tftp::Error::Timeout(_)))
+        ));
+        assert_eq!(sim.board().booted, None);
+    }
+}
@@ -0,0 +1,317 @@
+//! Capturing data with CASPER snapshot (snap/bitsnap) blocks.
+//!
+//! A snapshot block named `NAME` shows up in `/listdev` as three devices: `NAME_ctrl`, a control
+//! register; `NAME_status`, which says whether it's still capturing and how much it has captured;
+//! and `NAME_bram`, the memory the samples end up in. Capturing is a matter of arming it through
+//! the control register, waiting for the status to say it's done and then reading the BRAM.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bram::{self, Chunking},
+    register::{self, Bitfields},
+    tftp::Config,
+    Error,
+};
+
+/// Bits of the control register
+const ENABLE: u32 = 1 << 0;
+const TRIGGER: u32 = 1 << 1;
+const VALID: u32 = 1 << 2;
+const CIRCULAR: u32 = 1 << 3;
+
+/// The status register has this bit set while capturing, and the number of bytes captured in the
+/// rest
+const BUSY: u32 = 1 << 31;
+
+/// How long to wait between checks of the status register
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How to capture, see [`Snapshot::arm`]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Arm {
+    /// Start capturing right away instead of waiting for the trigger input
+    pub manual_trigger: bool,
+    /// Capture every clock instead of only when the valid input is high
+    pub manual_valid: bool,
+    /// Keep capturing into a ring buffer until the trigger, so we get what happened before it
+    pub circular: bool,
+}
+
+impl Arm {
+    fn control(&self) -> u32 {
+        let mut control = 0;
+        if self.manual_trigger {
+            control |= TRIGGER;
+        }
+        if self.manual_valid {
+            control |= VALID;
+        }
+        if self.circular {
+            control |= CIRCULAR;
+        }
+        control
+    }
+}
+
+/// A snapshot block in the running gateware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    name: String,
+    /// The size of the BRAM in bytes
+    bram_length: u32,
+}
+
+/// The bytes out of a snapshot's BRAM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capture {
+    pub bytes: Vec<u8>,
+}
+
+impl Capture {
+    /// Split the capture into samples, where each is as many 32-bit words as there are `layouts`,
+    /// and decode every word of a sample with its layout. Fields from all the words of a sample
+    /// are gathered into one map, so they should have different names.
+    pub fn decode(&self, layouts: &[Bitfields]) -> Result<Vec<BTreeMap<String, i64>>, Error> {
+        let width = layouts.len() * 4;
+        if width == 0 || self.bytes.len() % width != 0 {
+            let width = width.max(1);
+            return Err(Error::Length {
+                expected: (self.bytes.len() + width - 1) / width * width,
+                received: self.bytes.len(),
+            });
+        }
+        self.bytes
+            .chunks(width)
+            .map(|sample| {
+                let mut fields = BTreeMap::new();
+                for (word, layout) in sample.chunks(4).zip(layouts) {
+                    let word = u32::from_be_bytes(word.try_into().expect("Chunked to 4 bytes"));
+                    for field in layout.fields() {
+                        fields.insert(field.name.clone(), layout.get(word, &field.name)?);
+                    }
+                }
+                Ok(fields)
+            })
+            .collect()
+    }
+}
+
+impl Snapshot {
+    /// Find the snapshot block `name` in a `/listdev` map
+    pub fn find(name: &str, devices: &HashMap<String, (u32, u32)>) -> Result<Self, Error> {
+        let device = |suffix: &str| {
+            let device = format!("{}_{}", name, suffix);
+            devices
+                .get(&device)
+                .copied()
+                .ok_or(Error::UnknownDevice(device))
+        };
+        device("ctrl")?;
+        device("status")?;
+        let (_, bram_length) = device("bram")?;
+        Ok(Snapshot {
+            name: name.to_owned(),
+            bram_length,
+        })
+    }
+
+    /// Every snapshot block in a `/listdev` map
+    pub fn discover(devices: &HashMap<String, (u32, u32)>) -> Vec<Self> {
+        let mut snapshots: Vec<_> = devices
+            .keys()
+            .filter_map(|device| device.strip_suffix("_bram"))
+            .filter_map(|name| Self::find(name, devices).ok())
+            .collect();
+        snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+        snapshots
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The size of the BRAM in bytes, which is the most a single capture can hold
+    pub fn bram_length(&self) -> u32 {
+        self.bram_length
+    }
+
+    fn device(&self, suffix: &str) -> String {
+        format!("{}_{}", self.name, suffix)
+    }
+
+    /// Start a capture. The block starts on a rising edge of the enable bit, so we write the
+    /// options with it low and then again with it high.
+    pub fn arm(
+        &self,
+        options: Arm,
+        socket: &mut UdpSocket,
+        addr: SocketAddr,
+        config: &Config,
+    ) -> Result<(), Error> {
+        let ctrl = self.device("ctrl");
+        let control = options.control();
+        register::write_register(&ctrl, 0, control, socket, addr, config)?;
+        register::write_register(&ctrl, 0, control | ENABLE, socket, addr, config)
+    }
+
+    /// Poll the status register until the capture is done or `timeout` runs out, returning how
+    /// many bytes were captured
+    pub fn wait(
+        &self,
+        timeout: Duration,
+        socket: &mut UdpSocket,
+        addr: SocketAddr,
+        config: &Config,
+    ) -> Result<usize, Error> {
+        let status = self.device("status");
+        let start = Instant::now();
+        loop {
+            let word: u32 = register::read_register(&status, 0, socket, addr, config)?;
+            if word & BUSY == 0 {
+                return Ok((word & !BUSY) as usize);
+            }
+            if start.elapsed() >= timeout {
+                return Err(Error::CaptureTimeout(timeout));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Read the first `length` bytes of the BRAM, a chunk at a time so big captures don't hang on a
+    /// single transfer
+    pub fn read(
+        &self,
+        length: usize,
+        socket: &mut UdpSocket,
+        addr: SocketAddr,
+        config: &Config,
+    ) -> Result<Capture, Error> {
+        let length = length.min(self.bram_length as usize);
+        let bytes = bram::read_bram(
+            &self.device("bram"),
+            0,
+            length / 4,
+            Chunking::default(),
+            socket,
+            addr,
+            config,
+        )?;
+        Ok(Capture { bytes })
+    }
+
+    /// Arm, wait up to `timeout` for the capture to finish and read it back
+    pub fn capture(
+        &self,
+        options: Arm,
+        timeout: Duration,
+        socket: &mut UdpSocket,
+        addr: SocketAddr,
+        config: &Config,
+    ) -> Result<Capture, Error> {
+        self.arm(options, socket, addr, config)?;
+        let length = self.wait(timeout, socket, addr, config)?;
+        self.read(length, socket, addr, config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Board, Simulator};
+
+    fn devices() -> HashMap<String, (u32, u32)> {
+        [
+            ("adc_snap_bram", (0x1000, 0x800)),
+            ("adc_snap_ctrl", (0x2000, 4)),
+            ("adc_snap_status", (0x2004, 4)),
+            ("half_snap_bram", (0x3000, 0x800)),
+            ("sys_board_id", (0, 4)),
+        ]
+        .into_iter()
+        .map(|(name, device)| (name.to_owned(), device))
+        .collect()
+    }
+
+    #[test]
+    fn test_find() {
+        let devices = devices();
+        let snapshot = Snapshot::find("adc_snap", &devices).unwrap();
+        assert_eq!(snapshot.bram_length(), 0x800);
+        assert!(matches!(
+            Snapshot::find("half_snap", &devices),
+            Err(Error::UnknownDevice(name)) if name == "half_snap_ctrl"
+        ));
+        assert_eq!(Snapshot::discover(&devices), vec![snapshot]);
+    }
+
+    #[test]
+    fn test_control() {
+        assert_eq!(Arm::default().control(), 0);
+        let options = Arm {
+            manual_trigger: true,
+            circular: true,
+            ..Default::default()
+        };
+        assert_eq!(options.control(), TRIGGER | CIRCULAR);
+    }
+
+    #[test]
+    fn test_decode() {
+        let capture = Capture {
+            bytes: vec![0x00, 0x01, 0xFF, 0xFE, 0x00, 0x00, 0x00, 0x07],
+        };
+        let iq = Bitfields::new()
+            .field("i", 16, 16, true)
+            .field("q", 0, 16, true);
+        let samples = capture.decode(std::slice::from_ref(&iq)).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0]["i"], 1);
+        assert_eq!(samples[0]["q"], -2);
+        assert_eq!(samples[1]["q"], 7);
+        // Two words per sample
+        let count = Bitfields::new().field("count", 0, 32, false);
+        let samples = capture.decode(&[iq, count]).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0]["count"], 7);
+        // Doesn't divide into whole samples
+        let three = Bitfields::new().field("x", 0, 1, false);
+        assert!(capture
+            .decode(&[three.clone(), three.clone(), three])
+            .is_err());
+    }
+
+    #[test]
+    fn test_capture() {
+        let board = Board::new()
+            .with_device("adc_snap_bram", 0x10000, 0x10000)
+            .with_device("adc_snap_ctrl", 0x20000, 4)
+            .with_device("adc_snap_status", 0x20004, 4);
+        let sim = Simulator::start(board).unwrap();
+        // Bigger than a chunk, and done capturing all but the last word
+        let data: Vec<u8> = (0..0x10000).map(|i| (i % 251) as u8).collect();
+        sim.board().devices.get_mut("adc_snap_bram").unwrap().bytes = data.clone();
+        sim.board()
+            .devices
+            .get_mut("adc_snap_status")
+            .unwrap()
+            .bytes = 0xFFFCu32.to_be_bytes().to_vec();
+        let tapcp = sim.tapcp().unwrap();
+        let snapshot = tapcp.snapshot("adc_snap").unwrap();
+        let capture = tapcp
+            .capture(&snapshot, Arm::default(), Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(capture.bytes, data[..0xFFFC]);
+        // Less than a word is nothing at all, rather than the whole BRAM
+        let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let capture = snapshot
+            .read(2, &mut socket, sim.addr(), tapcp.config())
+            .unwrap();
+        assert!(capture.bytes.is_empty());
+    }
+}
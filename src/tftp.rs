@@ -1,18 +1,120 @@
 //! An IO-agnostic implementation of the TFTP standard (Revision 2), as defined by RFC 1350.
-//! Only the client is implemented here, because that's all we care about.
 //! The TFTP servers that TAPCP clients are running do not support the RFC 2348
-//! Blocksize Option, so all data blocks must be 512 bytes or fewer.
+//! Blocksize Option, so by default we ask for no options and all data blocks are 512 bytes or
+//! fewer. Other servers can be asked for [`Options`] (RFC 2347), and if they ignore or refuse them
+//! the transfer carries on without.
+//!
+//! The protocol itself lives in the sans-IO [`Transfer`], with blocking [`std::net::UdpSocket`]
+//! drivers (and tokio ones, with the `tokio` feature) on top of it. There's also a blocking
+//! [`Server`] for handing files to boards, serving whatever its [`Backend`] says they are.
 
-use std::{fmt::Display, net::UdpSocket, str::FromStr};
+mod server;
+mod transfer;
+
+pub use server::{Backend, Directory, Reply, Server};
+pub use transfer::{Transfer, Transmit};
+
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use anyhow::bail;
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::{FromPrimitive, ToPrimitive};
 
-const MAX_DATA: usize = 512;
+pub(crate) const MAX_DATA: usize = 512;
+
+/// The block sizes RFC 2348 allows
+const MIN_BLOCK_SIZE: usize = 8;
+const MAX_BLOCK_SIZE: usize = 65464;
+
+/// RFC 2347 options to ask the server for
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    /// Bytes per DATA block (RFC 2348), from 8 to 65464. The server may pick something smaller.
+    pub block_size: Option<u16>,
+    /// Seconds the server waits before resending (RFC 2349), which we then wait for too
+    pub timeout: Option<u8>,
+    /// Have the server tell us the size of a read, and tell it the size of a write (RFC 2349)
+    pub transfer_size: bool,
+}
+
+impl Options {
+    /// The name and value pairs to put in a request, where `size` is the length of a write
+    fn request(&self, size: Option<usize>) -> Vec<(String, String)> {
+        let mut options = vec![];
+        if let Some(block_size) = self.block_size {
+            options.push(("blksize".to_owned(), block_size.to_string()));
+        }
+        if let Some(timeout) = self.timeout {
+            options.push(("timeout".to_owned(), timeout.to_string()));
+        }
+        if self.transfer_size {
+            options.push(("tsize".to_owned(), size.unwrap_or(0).to_string()));
+        }
+        options
+    }
+}
+
+/// Knobs for how patient we are with the remote end
+#[derive(Debug, Copy, Clone)]
+pub struct Config {
+    /// How long we wait for a response to any single packet before resending it.
+    /// This must be nonzero, which [`crate::TapcpBuilder::build`] checks.
+    pub timeout: Duration,
+    /// How many times we resend a packet before giving up on the transfer
+    pub retries: usize,
+    /// Where block numbers wrap to for transfers of more than 65535 blocks
+    pub rollover: Rollover,
+    /// Options to ask for in each request, none by default
+    pub options: Options,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(500),
+            retries: 5,
+            rollover: Rollover::Zero,
+            options: Options::default(),
+        }
+    }
+}
+
+/// The block number that follows 65535 in transfers bigger than ~32 MiB.
+/// RFC 1350 doesn't say, so servers disagree on this and the client has to be told.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rollover {
+    Zero,
+    One,
+}
+
+impl Rollover {
+    /// The block number that comes after `block`
+    fn next(self, block: u16) -> u16 {
+        match block.checked_add(1) {
+            Some(next) => next,
+            None => match self {
+                Rollover::Zero => 0,
+                Rollover::One => 1,
+            },
+        }
+    }
+}
+
+/// Is `block` from earlier in the transfer than `current`? We consider anything up to half the
+/// block number space behind us as old, so this works across a rollover.
+fn is_behind(block: u16, current: u16) -> bool {
+    let distance = current.wrapping_sub(block);
+    distance != 0 && distance < 0x8000
+}
 
+/// How the data of a transfer is encoded
 #[derive(Debug, Copy, Clone)]
-pub(crate) enum Mode {
+pub enum Mode {
     NetASCII,
     Octet,
 }
@@ -60,6 +162,8 @@ pub enum ErrorCode {
     FileExists = 6,
     #[error("No such user")]
     NoUser = 7,
+    #[error("Option negotiation failed")]
+    BadOptions = 8,
 }
 
 /// Errors that can be thrown from TFTP interactions
@@ -77,6 +181,14 @@ pub enum Error {
     BadErrorCode,
     #[error("We didn't get back a block number we expected: {0}")]
     BadBlock(u16),
+    #[error("The server acknowledged an option we can't go along with: {0}")]
+    BadOptions(String),
+    #[error("We didn't hear back after {0} retries")]
+    Timeout(usize),
+    #[error("A string in the payload wasn't valid UTF-8")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
 #[derive(Debug)]
@@ -84,10 +196,12 @@ pub(crate) enum Payload {
     Read {
         filename: String,
         mode: Mode,
+        options: Vec<(String, String)>,
     },
     Write {
         filename: String,
         mode: Mode,
+        options: Vec<(String, String)>,
     },
     Data {
         block: u16,
@@ -100,24 +214,70 @@ pub(crate) enum Payload {
         error_code: ErrorCode,
         error_msg: String,
     },
+    OptionAck {
+        options: Vec<(String, String)>,
+    },
+}
+
+/// Append RFC 2347 options to a packet as alternating NUL terminated names and values
+fn pack_options(bytes: &mut Vec<u8>, options: &[(String, String)]) {
+    for (name, value) in options {
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.push(0);
+        bytes.extend_from_slice(value.as_bytes());
+        bytes.push(0);
+    }
+}
+
+/// Parse the RFC 2347 options that fill the rest of a packet. Names are case-insensitive, so we
+/// lowercase them.
+fn unpack_options(mut bytes: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    let mut strings = vec![];
+    while !bytes.is_empty() {
+        let null_idx = bytes
+            .iter()
+            .position(|&c| c == b'\0')
+            .ok_or(Error::Incomplete)?;
+        strings.push(std::str::from_utf8(&bytes[..null_idx])?.to_string());
+        bytes = &bytes[(null_idx + 1)..];
+    }
+    if strings.len() % 2 != 0 {
+        return Err(Error::Incomplete);
+    }
+    Ok(strings
+        .chunks(2)
+        .map(|pair| (pair[0].to_ascii_lowercase(), pair[1].clone()))
+        .collect())
 }
 
 impl Payload {
     /// Take an instance of a TFTP payload, and construct the byte payload to send over UDP
-    fn pack(&self) -> Vec<u8> {
+    pub(crate) fn pack(&self) -> Vec<u8> {
         let mut bytes = vec![];
-        if let Payload::Read { filename, mode } = self {
+        if let Payload::Read {
+            filename,
+            mode,
+            options,
+        } = self
+        {
             bytes.extend_from_slice(&1u16.to_be_bytes());
             bytes.extend_from_slice(filename.as_bytes());
             bytes.push(0u8);
             bytes.extend_from_slice(mode.to_string().as_bytes());
             bytes.push(0u8);
-        } else if let Payload::Write { filename, mode } = self {
+            pack_options(&mut bytes, options);
+        } else if let Payload::Write {
+            filename,
+            mode,
+            options,
+        } = self
+        {
             bytes.extend_from_slice(&2u16.to_be_bytes());
             bytes.extend_from_slice(filename.as_bytes());
             bytes.push(0u8);
             bytes.extend_from_slice(mode.to_string().as_bytes());
             bytes.push(0u8);
+            pack_options(&mut bytes, options);
         } else if let Payload::Data { block, data } = self {
             bytes.extend_from_slice(&3u16.to_be_bytes());
             bytes.extend_from_slice(&block.to_be_bytes());
@@ -136,16 +296,19 @@ impl Payload {
             );
             bytes.extend_from_slice(error_msg.as_bytes());
             bytes.push(0);
+        } else if let Payload::OptionAck { options } = self {
+            bytes.extend_from_slice(&6u16.to_be_bytes());
+            pack_options(&mut bytes, options);
         }
 
         bytes
     }
 
     /// Given bytes from UDP, construct an instance of a TFTP payload
-    fn unpack(bytes: &[u8]) -> anyhow::Result<Self> {
+    pub(crate) fn unpack(bytes: &[u8]) -> Result<Self, Error> {
         // The smallest this can be is 4 bytes (ACK), so if it's less than that, bail
         if bytes.len() < 4 {
-            bail!(Error::Incomplete);
+            return Err(Error::Incomplete);
         }
         // First two bytes determine the op code
         let opcode = u16::from_be_bytes(
@@ -172,24 +335,34 @@ impl Payload {
                     .ok_or(Error::Incomplete)?;
                 let mode_str = std::str::from_utf8(&bytes[..mode_null_idx])?;
                 let mode = Mode::from_str(mode_str)?;
+                // Anything after that is options
+                let options = unpack_options(&bytes[(mode_null_idx + 1)..])?;
                 if opcode == 1 {
-                    Payload::Read { filename, mode }
+                    Payload::Read {
+                        filename,
+                        mode,
+                        options,
+                    }
                 } else {
-                    Payload::Write { filename, mode }
+                    Payload::Write {
+                        filename,
+                        mode,
+                        options,
+                    }
                 }
             }
             // Data
             3 => {
-                let block = u16::from_be_bytes(bytes[..2].try_into()?);
+                let block = u16::from_be_bytes([bytes[0], bytes[1]]);
                 let data = bytes[2..].to_vec();
                 Payload::Data { block, data }
             }
             4 => {
-                let block = u16::from_be_bytes(bytes[..2].try_into()?);
+                let block = u16::from_be_bytes([bytes[0], bytes[1]]);
                 Payload::Ack { block }
             }
             5 => {
-                let raw_err_code = u16::from_be_bytes(bytes[..2].try_into()?);
+                let raw_err_code = u16::from_be_bytes([bytes[0], bytes[1]]);
                 let error_code = ErrorCode::from_u16(raw_err_code).ok_or(Error::BadErrorCode)?;
                 // Consume more bytes, skipping the null
                 let bytes = &bytes[2..];
@@ -203,117 +376,166 @@ impl Payload {
                     error_msg,
                 }
             }
-            _ => bail!(Error::BadOpcode),
+            6 => Payload::OptionAck {
+                options: unpack_options(bytes)?,
+            },
+            _ => return Err(Error::BadOpcode),
         })
     }
 }
 
-// In the context of TAPCP, these are quick operations. No need to use progress bars here
-/// Read from a filename and get back the bytes
-pub(crate) fn read(filename: &str, socket: &mut UdpSocket, mode: Mode) -> anyhow::Result<Vec<u8>> {
-    // Create the buffer we will use to read into. The biggest this can be is 512 bytes of data, plus 4 bytes of header
-    let mut buf = [0u8; 516];
-    // And the output vector we'll store bytes in
-    let mut output = vec![];
-    // We start the transfer with the RRQ (read request) and will recieve the first data packet
-    let rrq = Payload::Read {
-        filename: filename.to_string(),
-        mode,
-    };
-    // Write out this payload
-    socket.send(&rrq.pack())?;
+/// Run `transfer` to completion over a blocking `socket`
+pub(crate) fn drive(transfer: &mut Transfer, socket: &UdpSocket) -> Result<(), Error> {
+    // Create the buffer we will use to read into. The biggest this can be is the biggest block
+    // size we could negotiate, plus 4 bytes of header
+    let mut buf = vec![0u8; 4 + MAX_BLOCK_SIZE];
+    let mut deadline = Instant::now();
     loop {
-        // Read and deserialize the responses
-        let nbytes = socket.recv(&mut buf)?;
-        let resp = Payload::unpack(&buf[..nbytes])?;
-        match resp {
-            Payload::Data { block, ref data } => {
-                // Copy out the bytes
-                output.extend_from_slice(data);
-                // Send the ACK
-                let ack = Payload::Ack { block };
-                socket.send(&ack.pack())?;
-                // Check end of data condition
-                if data.len() < MAX_DATA {
-                    break;
+        while let Some(Transmit { to, datagram }) = transfer.poll_transmit() {
+            socket.send_to(&datagram, to)?;
+        }
+        if transfer.is_finished() {
+            return Ok(());
+        }
+        // Only sending restarts the clock, so strays and duplicates can't hold off a resend. The
+        // timeout can change when the server agrees to a timeout option.
+        if transfer.take_sent() {
+            deadline = Instant::now() + transfer.timeout();
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let handled = if remaining.is_zero() {
+            transfer.handle_timeout()
+        } else {
+            socket.set_read_timeout(Some(remaining))?;
+            match socket.recv_from(&mut buf) {
+                Ok((nbytes, from)) => transfer.handle_datagram(from, &buf[..nbytes]),
+                // Unix reports a read timeout as WouldBlock, Windows as TimedOut
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    transfer.handle_timeout()
                 }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        if let Err(e) = handled {
+            // Tell the server why we're giving up, if the transfer has something to say
+            while let Some(Transmit { to, datagram }) = transfer.poll_transmit() {
+                let _ = socket.send_to(&datagram, to);
             }
-            Payload::Error {
-                error_code,
-                error_msg,
-            } => bail!(Error::ErrorResponse(error_code, error_msg)),
-            _ => unreachable!(),
+            return Err(e);
         }
     }
-    Ok(output)
 }
 
-/// Write the bytes from `data` to the TFTP server at `filename`
-pub(crate) fn write(filename: &str, data: &[u8], socket: &mut UdpSocket) -> anyhow::Result<()> {
-    // We start the transfer with the WRQ (write request)
-    let wrq = Payload::Write {
-        filename: filename.to_string(),
-        mode: Mode::Octet,
-    };
-    // Create the buffer we will use to read into.
-    // We will only ever get back ACK or error messages.
-    // In the case of error messages, we will limit the string payload to 512 bytes, like the data
-    let mut buf = [0u8; 516];
-    // Write out this payload
-    socket.send(&wrq.pack())?;
-    // We should receive either an ACK of block 0 or ERROR
-    let nbytes = socket.recv(&mut buf)?;
-    match Payload::unpack(&buf[..nbytes])? {
-        Payload::Ack { block } => {
-            if block != 0 {
-                bail!(Error::BadBlock(block))
-            }
+// In the context of TAPCP, these are quick operations. No need to use progress bars here
+/// Read from a filename on the TFTP server at `server` and get back the bytes
+pub(crate) fn read(
+    filename: &str,
+    socket: &mut UdpSocket,
+    server: SocketAddr,
+    mode: Mode,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let mut transfer = Transfer::read(filename, mode, server, *config);
+    drive(&mut transfer, socket)?;
+    Ok(transfer.into_data())
+}
+
+/// Write the bytes from `data` to `filename` on the TFTP server at `server`
+pub(crate) fn write(
+    filename: &str,
+    data: &[u8],
+    socket: &mut UdpSocket,
+    server: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let mut transfer = Transfer::write(filename, data.to_vec(), server, *config);
+    drive(&mut transfer, socket)
+}
+
+/// Run `transfer` to completion over a tokio `socket`
+#[cfg(feature = "tokio")]
+async fn drive_async(transfer: &mut Transfer, socket: &tokio::net::UdpSocket) -> Result<(), Error> {
+    let mut buf = vec![0u8; 4 + MAX_BLOCK_SIZE];
+    let mut deadline = Instant::now();
+    loop {
+        while let Some(Transmit { to, datagram }) = transfer.poll_transmit() {
+            socket.send_to(&datagram, to).await?;
         }
-        Payload::Error {
-            error_code,
-            error_msg,
-        } => bail!(Error::ErrorResponse(error_code, error_msg)),
-        _ => unreachable!(),
-    }
-    // Assuming we survived this, we can start actually sending the data
-    for (i, chunk) in data.chunks(MAX_DATA).enumerate() {
-        // Send the (i+1)th chunk (because chunks are 1-indexed)
-        // Prepare the data payload
-        let data_payload = Payload::Data {
-            block: (i + 1) as u16,
-            data: chunk.to_vec(),
+        if transfer.is_finished() {
+            return Ok(());
+        }
+        if transfer.take_sent() {
+            deadline = Instant::now() + transfer.timeout();
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let handled = match tokio::time::timeout(remaining, socket.recv_from(&mut buf)).await {
+            Ok(Ok((nbytes, from))) => transfer.handle_datagram(from, &buf[..nbytes]),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => transfer.handle_timeout(),
         };
-        // Send
-        socket.send(&data_payload.pack())?;
-        // Wait for the ACK
-        let nbytes = socket.recv(&mut buf)?;
-        // Which should match the index we just sent
-        match Payload::unpack(&buf[..nbytes])? {
-            Payload::Ack { block } => {
-                if block as usize != i + 1 {
-                    bail!(Error::BadBlock(block))
-                }
+        if let Err(e) = handled {
+            while let Some(Transmit { to, datagram }) = transfer.poll_transmit() {
+                let _ = socket.send_to(&datagram, to).await;
             }
-            Payload::Error {
-                error_code,
-                error_msg,
-            } => bail!(Error::ErrorResponse(error_code, error_msg)),
-            _ => unreachable!(),
+            return Err(e);
         }
     }
-    // If we survived this, then we've sent everything we needed to!
-    Ok(())
+}
+
+/// Async version of [`read`]
+#[cfg(feature = "tokio")]
+pub(crate) async fn read_async(
+    filename: &str,
+    socket: &mut tokio::net::UdpSocket,
+    server: SocketAddr,
+    mode: Mode,
+    config: &Config,
+) -> Result<Vec<u8>, Error> {
+    let mut transfer = Transfer::read(filename, mode, server, *config);
+    drive_async(&mut transfer, socket).await?;
+    Ok(transfer.into_data())
+}
+
+/// Async version of [`write`]
+#[cfg(feature = "tokio")]
+pub(crate) async fn write_async(
+    filename: &str,
+    data: &[u8],
+    socket: &mut tokio::net::UdpSocket,
+    server: SocketAddr,
+    config: &Config,
+) -> Result<(), Error> {
+    let mut transfer = Transfer::write(filename, data.to_vec(), server, *config);
+    drive_async(&mut transfer, socket).await
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use std::thread;
+
+    /// A client socket and a loopback "server" socket we can script by hand
+    fn loopback() -> (UdpSocket, UdpSocket, SocketAddr) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        (client, server, addr)
+    }
+
+    fn quick() -> Config {
+        Config {
+            timeout: Duration::from_millis(50),
+            retries: 2,
+            ..Default::default()
+        }
+    }
 
     #[test]
     fn test_pack_read() {
         let payload = Payload::Read {
             filename: "/foo".to_owned(),
             mode: Mode::Octet,
+            options: vec![],
         };
         let packed = payload.pack();
         assert_eq!(
@@ -327,6 +549,7 @@ mod tests {
         let payload = Payload::Write {
             filename: "/foo".to_owned(),
             mode: Mode::Octet,
+            options: vec![],
         };
         let packed = payload.pack();
         assert_eq!(
@@ -378,6 +601,34 @@ mod tests {
         assert_eq!(payload, Payload::unpack(&payload).unwrap().pack());
     }
 
+    #[test]
+    fn test_options() {
+        let rrq = b"\x00\x01/foo\x00octet\x00BlkSize\x001024\x00tsize\x000\x00";
+        let payload = Payload::unpack(rrq).unwrap();
+        let expected = vec![
+            ("blksize".to_owned(), "1024".to_owned()),
+            ("tsize".to_owned(), "0".to_owned()),
+        ];
+        assert!(matches!(&payload, Payload::Read { options, .. } if *options == expected));
+        let oack = Payload::OptionAck { options: expected };
+        let packed = oack.pack();
+        assert_eq!(packed, b"\x00\x06blksize\x001024\x00tsize\x000\x00");
+        assert_eq!(packed, Payload::unpack(&packed).unwrap().pack());
+        // A name without a value
+        assert!(Payload::unpack(b"\x00\x06blksize\x00").is_err());
+        let options = Options {
+            block_size: Some(1428),
+            timeout: Some(2),
+            transfer_size: true,
+        };
+        assert_eq!(
+            options.request(Some(99)),
+            [("blksize", "1428"), ("timeout", "2"), ("tsize", "99")]
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        );
+        assert!(Options::default().request(None).is_empty());
+    }
+
     #[test]
     fn test_roundtrip_data() {
         let payload = vec![0, 3, 0, 1, 0xDE, 0xAD, 0xBE, 0xEF];
@@ -395,4 +646,305 @@ mod tests {
         let payload = vec![0, 5, 0, 3, b'F', b'u', b'l', b'l', 0];
         assert_eq!(payload, Payload::unpack(&payload).unwrap().pack());
     }
+
+    #[test]
+    fn test_read_resends_lost_rrq() {
+        let (mut client, server, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 516];
+            // Drop the first RRQ on the floor
+            server.recv_from(&mut buf).unwrap();
+            // And answer the retransmission
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            assert!(matches!(
+                Payload::unpack(&buf[..n]).unwrap(),
+                Payload::Read { .. }
+            ));
+            let data = Payload::Data {
+                block: 1,
+                data: vec![1, 2, 3],
+            };
+            server.send_to(&data.pack(), from).unwrap();
+            let (n, _) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..n], &[0, 4, 0, 1]);
+        });
+        let bytes = read("/foo", &mut client, addr, Mode::Octet, &quick()).unwrap();
+        assert_eq!(bytes, vec![1, 2, 3]);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_resends_lost_data() {
+        let (mut client, server, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let mut buf = [0u8; 516];
+            let (_, from) = server.recv_from(&mut buf).unwrap();
+            server
+                .send_to(&Payload::Ack { block: 0 }.pack(), from)
+                .unwrap();
+            // Drop the first copy of block 1
+            server.recv_from(&mut buf).unwrap();
+            let (n, _) = server.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..n], &[0, 3, 0, 1, 0xDE, 0xAD]);
+            server
+                .send_to(&Payload::Ack { block: 1 }.pack(), from)
+                .unwrap();
+        });
+        write("/foo", &[0xDE, 0xAD], &mut client, addr, &quick()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_gives_up() {
+        let (mut client, server, addr) = loopback();
+        let err = read("/foo", &mut client, addr, Mode::Octet, &quick()).unwrap_err();
+        assert!(matches!(err, Error::Timeout(2)));
+        // We should have sent the original RRQ and two retries
+        server.set_nonblocking(true).unwrap();
+        let mut buf = [0u8; 516];
+        let mut sent = 0;
+        while server.recv(&mut buf).is_ok() {
+            sent += 1;
+        }
+        assert_eq!(sent, 3);
+    }
+
+    #[test]
+    fn test_read_gives_up_despite_strays() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        };
+        let (mut client, server, addr) = loopback();
+        let target = client.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                // Junk that doesn't decode, more often than the client's timeout, until it gives up
+                let start = Instant::now();
+                while !stop.load(Ordering::Relaxed) && start.elapsed() < Duration::from_secs(5) {
+                    server.send_to(&[0, 42, 1, 2], target).unwrap();
+                    thread::sleep(Duration::from_millis(10));
+                }
+            })
+        };
+        let start = Instant::now();
+        let err = read("/foo", &mut client, addr, Mode::Octet, &quick()).unwrap_err();
+        stop.store(true, Ordering::Relaxed);
+        handle.join().unwrap();
+        assert!(matches!(err, Error::Timeout(2)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Receive and decode the next packet sent to our scripted server
+    pub(crate) fn next_packet(server: &UdpSocket) -> (Payload, std::net::SocketAddr) {
+        let mut buf = [0u8; 516];
+        let (n, from) = server.recv_from(&mut buf).unwrap();
+        (Payload::unpack(&buf[..n]).unwrap(), from)
+    }
+
+    pub(crate) fn data(block: u16, len: usize) -> Vec<u8> {
+        Payload::Data {
+            block,
+            data: vec![block as u8; len],
+        }
+        .pack()
+    }
+
+    #[test]
+    fn test_read_duplicate_data() {
+        let (mut client, server, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            // Captured sequence where our ACK of block 1 was delayed, and the server resent it
+            for packet in [data(1, 512), data(1, 512), data(2, 10)] {
+                server.send_to(&packet, from).unwrap();
+            }
+            let acks: Vec<_> = (0..3)
+                .map(|_| match next_packet(&server).0 {
+                    Payload::Ack { block } => block,
+                    p => panic!("Expected an ACK, got {:?}", p),
+                })
+                .collect();
+            // The duplicate must be ACKed again
+            assert_eq!(acks, vec![1, 1, 2]);
+        });
+        let bytes = read("/foo", &mut client, addr, Mode::Octet, &quick()).unwrap();
+        assert_eq!(bytes.len(), 522);
+        assert!(bytes[..512].iter().all(|&b| b == 1));
+        assert!(bytes[512..].iter().all(|&b| b == 2));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_out_of_order_data() {
+        let (mut client, server, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            // Block 2 overtook block 1, and was then resent after the ACK of block 1
+            for packet in [data(2, 10), data(1, 512)] {
+                server.send_to(&packet, from).unwrap();
+            }
+            assert!(matches!(next_packet(&server).0, Payload::Ack { block: 1 }));
+            server.send_to(&data(2, 10), from).unwrap();
+            assert!(matches!(next_packet(&server).0, Payload::Ack { block: 2 }));
+        });
+        let bytes = read("/foo", &mut client, addr, Mode::Octet, &quick()).unwrap();
+        assert_eq!(bytes.len(), 522);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_duplicate_ack() {
+        let (mut client, server, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            let ack = |block| Payload::Ack { block }.pack();
+            server.send_to(&ack(0), from).unwrap();
+            assert!(matches!(
+                next_packet(&server).0,
+                Payload::Data { block: 1, .. }
+            ));
+            // Our ACK of the WRQ got duplicated on the way
+            server.send_to(&ack(0), from).unwrap();
+            server.send_to(&ack(1), from).unwrap();
+            // The next thing we see must be block 2, not a resend of block 1
+            assert!(matches!(
+                next_packet(&server).0,
+                Payload::Data { block: 2, .. }
+            ));
+            server.send_to(&ack(1), from).unwrap();
+            server.send_to(&ack(2), from).unwrap();
+            // And nothing else should show up
+            server
+                .set_read_timeout(Some(Duration::from_millis(20)))
+                .unwrap();
+            assert!(server.recv_from(&mut [0u8; 516]).is_err());
+        });
+        write("/foo", &[0u8; 600], &mut client, addr, &quick()).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_follows_server_tid() {
+        let (mut client, listener, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&listener);
+            // Real servers answer from a fresh port
+            let transfer = UdpSocket::bind("127.0.0.1:0").unwrap();
+            transfer.send_to(&data(1, 512), from).unwrap();
+            assert!(matches!(
+                next_packet(&transfer).0,
+                Payload::Ack { block: 1 }
+            ));
+            transfer.send_to(&data(2, 0), from).unwrap();
+            assert!(matches!(
+                next_packet(&transfer).0,
+                Payload::Ack { block: 2 }
+            ));
+        });
+        let bytes = read("/foo", &mut client, addr, Mode::Octet, &quick()).unwrap();
+        assert_eq!(bytes.len(), 512);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_rejects_stray_tid() {
+        let (mut client, server, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            server.send_to(&data(1, 512), from).unwrap();
+            assert!(matches!(next_packet(&server).0, Payload::Ack { block: 1 }));
+            // Someone else butts in with the block we're waiting for
+            let stray = UdpSocket::bind("127.0.0.1:0").unwrap();
+            stray.send_to(&data(2, 5), from).unwrap();
+            assert!(matches!(
+                next_packet(&stray).0,
+                Payload::Error {
+                    error_code: ErrorCode::UnknownID,
+                    ..
+                }
+            ));
+            server.send_to(&data(2, 10), from).unwrap();
+            assert!(matches!(next_packet(&server).0, Payload::Ack { block: 2 }));
+        });
+        let bytes = read("/foo", &mut client, addr, Mode::Octet, &quick()).unwrap();
+        // Only the real server's bytes should have made it in
+        assert_eq!(bytes.len(), 522);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_rollover() {
+        assert_eq!(Rollover::Zero.next(41), 42);
+        assert_eq!(Rollover::Zero.next(u16::MAX), 0);
+        assert_eq!(Rollover::One.next(u16::MAX), 1);
+        assert!(is_behind(u16::MAX, 0));
+        assert!(is_behind(u16::MAX, 1));
+        assert!(!is_behind(1, u16::MAX));
+        assert!(!is_behind(7, 7));
+    }
+
+    /// Enough full blocks to wrap the block number, plus a short one to finish
+    const WRAPPING_BLOCKS: usize = u16::MAX as usize + 3;
+
+    #[test]
+    fn test_read_rollover() {
+        let (mut client, server, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            let mut block = 0u16;
+            for i in 0..WRAPPING_BLOCKS {
+                block = Rollover::One.next(block);
+                let len = if i == WRAPPING_BLOCKS - 1 { 1 } else { 512 };
+                server.send_to(&data(block, len), from).unwrap();
+                match next_packet(&server).0 {
+                    Payload::Ack { block: acked } => assert_eq!(acked, block),
+                    p => panic!("Expected an ACK, got {:?}", p),
+                }
+            }
+            // Make sure we actually wrapped to 1
+            assert_eq!(block, 3);
+        });
+        let config = Config {
+            rollover: Rollover::One,
+            ..quick()
+        };
+        let bytes = read("/foo", &mut client, addr, Mode::Octet, &config).unwrap();
+        assert_eq!(bytes.len(), (WRAPPING_BLOCKS - 1) * 512 + 1);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_write_rollover() {
+        let (mut client, server, addr) = loopback();
+        let handle = thread::spawn(move || {
+            let (_, from) = next_packet(&server);
+            server
+                .send_to(&Payload::Ack { block: 0 }.pack(), from)
+                .unwrap();
+            let mut received = 0;
+            let last_block = loop {
+                match next_packet(&server).0 {
+                    Payload::Data { block, data } => {
+                        received += data.len();
+                        server
+                            .send_to(&Payload::Ack { block }.pack(), from)
+                            .unwrap();
+                        if data.len() < 512 {
+                            break block;
+                        }
+                    }
+                    p => panic!("Expected DATA, got {:?}", p),
+                }
+            };
+            // 65538 % 65536
+            assert_eq!(last_block, 2);
+            received
+        });
+        let payload = vec![0u8; (WRAPPING_BLOCKS - 1) * 512 + 1];
+        write("/foo", &payload, &mut client, addr, &quick()).unwrap();
+        assert_eq!(handle.join().unwrap(), payload.len());
+    }
 }
@@ -0,0 +1,515 @@
+//! A blocking TFTP server.
+//!
+//! [`Server`] takes requests on one socket and carries each out on a thread of its own with a
+//! fresh socket (and so a fresh transfer ID), as RFC 1350 asks. What the files actually are is up
+//! to the [`Backend`], so the same server can hand bitstreams to boards out of a [`Directory`] or
+//! stand in for a board in tests.
+//!
+//! Both modes are accepted, but netascii requests are served as raw octets, with no line ending
+//! conversion. Options (RFC 2347) in requests are ignored, so clients carry on with the defaults.
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{Config, ErrorCode, Payload, MAX_DATA};
+
+/// How often the listener checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A TFTP error to send back, as the code and the message
+pub type Reply = (ErrorCode, String);
+
+/// Where a [`Server`] gets the files it reads from and puts the files it's written
+pub trait Backend: Send + Sync + 'static {
+    /// The whole contents of `filename`
+    fn read(&self, filename: &str) -> Result<Vec<u8>, Reply>;
+
+    /// Check a write to `filename` can go ahead before taking any data
+    fn check_write(&self, _filename: &str) -> Result<(), Reply> {
+        Ok(())
+    }
+
+    /// Store all of `data` as `filename`. The client only hears the write is done after this
+    /// returns, so an error here still gets back to it.
+    fn write(&self, filename: &str, data: &[u8]) -> Result<(), Reply>;
+}
+
+/// Files under a directory on disk. Filenames are taken relative to it, with any leading `/`
+/// ignored, and can't climb out of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Directory {
+    root: PathBuf,
+}
+
+impl Directory {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Directory { root: root.into() }
+    }
+
+    fn path(&self, filename: &str) -> Result<PathBuf, Reply> {
+        let relative = Path::new(filename.trim_start_matches('/'));
+        let mut components = relative.components().peekable();
+        if components.peek().is_none()
+            || !components.all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err((ErrorCode::AccessViolation, filename.to_owned()));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+fn io_reply(e: io::Error) -> Reply {
+    match e.kind() {
+        ErrorKind::NotFound => (ErrorCode::NotFound, e.to_string()),
+        ErrorKind::PermissionDenied => (ErrorCode::AccessViolation, e.to_string()),
+        _ => (ErrorCode::NotDefined, e.to_string()),
+    }
+}
+
+impl Backend for Directory {
+    fn read(&self, filename: &str) -> Result<Vec<u8>, Reply> {
+        fs::read(self.path(filename)?).map_err(io_reply)
+    }
+
+    fn check_write(&self, filename: &str) -> Result<(), Reply> {
+        self.path(filename).map(|_| ())
+    }
+
+    fn write(&self, filename: &str, data: &[u8]) -> Result<(), Reply> {
+        fs::write(self.path(filename)?, data).map_err(io_reply)
+    }
+}
+
+/// Wait for a packet from `peer` on `socket`, turning anyone else away. `None` on a timeout.
+fn receive(socket: &UdpSocket, peer: SocketAddr) -> io::Result<Option<Payload>> {
+    let mut buf = [0; 4 + MAX_DATA];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((n, from)) if from == peer => {
+                // Anything we can't decode is as good as lost
+                if let Ok(payload) = Payload::unpack(&buf[..n]) {
+                    return Ok(Some(payload));
+                }
+            }
+            Ok((_, from)) => {
+                let error = Payload::Error {
+                    error_code: ErrorCode::UnknownID,
+                    error_msg: String::new(),
+                };
+                socket.send_to(&error.pack(), from)?;
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn send_error(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    (error_code, error_msg): Reply,
+) -> io::Result<()> {
+    let error = Payload::Error {
+        error_code,
+        error_msg,
+    };
+    socket.send_to(&error.pack(), peer).map(|_| ())
+}
+
+/// Send `data` to `peer` a block at a time
+fn serve_read(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    data: &[u8],
+    config: &Config,
+) -> io::Result<()> {
+    // A transfer that's a multiple of the block size ends with an empty block
+    let blocks = data.len() / MAX_DATA + 1;
+    let mut block = 1;
+    for i in 0..blocks {
+        let chunk = &data[(i * MAX_DATA).min(data.len())..((i + 1) * MAX_DATA).min(data.len())];
+        let packet = Payload::Data {
+            block,
+            data: chunk.to_vec(),
+        }
+        .pack();
+        let mut attempts = 0;
+        socket.send_to(&packet, peer)?;
+        let mut sent = Instant::now();
+        loop {
+            match receive(socket, peer)? {
+                Some(Payload::Ack { block: acked }) if acked == block => break,
+                Some(Payload::Error { .. }) => return Ok(()),
+                // Duplicate or stale acknowledgements, which mustn't put off resending if the
+                // client keeps sending them
+                Some(_) if sent.elapsed() < config.timeout => {}
+                _ if attempts < config.retries => {
+                    attempts += 1;
+                    socket.send_to(&packet, peer)?;
+                    sent = Instant::now();
+                }
+                _ => return Ok(()),
+            }
+        }
+        block = config.rollover.next(block);
+    }
+    Ok(())
+}
+
+/// Take a write from `peer`, handing all the data to `finish` before acknowledging the last block
+/// so the client never sees the write done before it is
+fn serve_write<F>(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    config: &Config,
+    finish: F,
+) -> io::Result<()>
+where
+    F: FnOnce(&[u8]) -> Result<(), Reply>,
+{
+    let mut data = vec![];
+    let mut block: u16 = 0;
+    let mut ack = Payload::Ack { block }.pack();
+    let mut attempts = 0;
+    socket.send_to(&ack, peer)?;
+    loop {
+        match receive(socket, peer)? {
+            Some(Payload::Data {
+                block: got,
+                data: chunk,
+            }) if got == config.rollover.next(block) => {
+                block = got;
+                attempts = 0;
+                data.extend_from_slice(&chunk);
+                ack = Payload::Ack { block }.pack();
+                if chunk.len() < MAX_DATA {
+                    break;
+                }
+                socket.send_to(&ack, peer)?;
+            }
+            Some(Payload::Error { .. }) => return Ok(()),
+            // A block we already have, so our acknowledgement was lost
+            Some(Payload::Data { .. }) => {
+                socket.send_to(&ack, peer)?;
+            }
+            Some(_) => {}
+            None if attempts < config.retries => {
+                attempts += 1;
+                socket.send_to(&ack, peer)?;
+            }
+            None => return Ok(()),
+        }
+    }
+    if let Err(reply) = finish(&data) {
+        return send_error(socket, peer, reply);
+    }
+    socket.send_to(&ack, peer)?;
+    // Hang around in case our last acknowledgement was lost and the client sends the block again
+    while let Some(payload) = receive(socket, peer)? {
+        if matches!(payload, Payload::Data { block: got, .. } if got == block) {
+            socket.send_to(&ack, peer)?;
+        }
+    }
+    Ok(())
+}
+
+/// Carry out one request from `peer` on a fresh socket bound to `ip`
+fn serve<B: Backend>(
+    request: Payload,
+    peer: SocketAddr,
+    ip: IpAddr,
+    backend: &B,
+    config: &Config,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind((ip, 0))?;
+    socket.set_read_timeout(Some(config.timeout))?;
+    match request {
+        Payload::Read { filename, .. } => match backend.read(&filename) {
+            Ok(data) => serve_read(&socket, peer, &data, config),
+            Err(reply) => send_error(&socket, peer, reply),
+        },
+        Payload::Write { filename, .. } => {
+            if let Err(reply) = backend.check_write(&filename) {
+                return send_error(&socket, peer, reply);
+            }
+            serve_write(&socket, peer, config, |data| backend.write(&filename, data))
+        }
+        _ => send_error(
+            &socket,
+            peer,
+            (ErrorCode::IllegalOp, "Expected a request".to_owned()),
+        ),
+    }
+}
+
+/// A TFTP server for the files in a [`Backend`]
+#[derive(Debug)]
+pub struct Server<B> {
+    listener: UdpSocket,
+    backend: Arc<B>,
+    config: Config,
+}
+
+impl<B: Backend> Server<B> {
+    /// Listen for requests on `addr`. `config` sets how long each transfer waits on the client
+    /// and how many times it resends, as well as how block numbers roll over.
+    pub fn bind(addr: impl ToSocketAddrs, backend: B, config: Config) -> io::Result<Self> {
+        let listener = UdpSocket::bind(addr)?;
+        listener.set_read_timeout(Some(POLL_INTERVAL))?;
+        Ok(Server {
+            listener,
+            backend: Arc::new(backend),
+            config,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Take requests until `stop` is set, serving each on its own thread
+    pub fn serve(&self, stop: &AtomicBool) -> io::Result<()> {
+        let ip = self.listener.local_addr()?.ip();
+        let mut buf = [0; 4 + MAX_DATA];
+        while !stop.load(Ordering::Relaxed) {
+            let (n, peer) = match self.listener.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            let request = match Payload::unpack(&buf[..n]) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            let backend = self.backend.clone();
+            let config = self.config;
+            thread::spawn(move || serve(request, peer, ip, &*backend, &config));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tftp::{self, Error, Mode};
+    use std::{collections::HashMap, sync::Mutex};
+
+    #[derive(Default)]
+    struct Files(Mutex<HashMap<String, Vec<u8>>>);
+
+    impl Backend for Files {
+        fn read(&self, filename: &str) -> Result<Vec<u8>, Reply> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(filename)
+                .cloned()
+                .ok_or((ErrorCode::NotFound, filename.to_owned()))
+        }
+
+        fn check_write(&self, filename: &str) -> Result<(), Reply> {
+            if filename.starts_with("/ro/") {
+                return Err((ErrorCode::AccessViolation, "Read only".to_owned()));
+            }
+            Ok(())
+        }
+
+        fn write(&self, filename: &str, data: &[u8]) -> Result<(), Reply> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(filename.to_owned(), data.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Run `f` against a server for `backend` on a background thread
+    fn with_server<B: Backend, T>(backend: B, f: impl FnOnce(&Server<B>, SocketAddr) -> T) -> T {
+        let config = Config {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let server = Server::bind("127.0.0.1:0", backend, config).unwrap();
+        let addr = server.local_addr().unwrap();
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| server.serve(&stop).unwrap());
+            let result = f(&server, addr);
+            stop.store(true, Ordering::Relaxed);
+            result
+        })
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        with_server(Files::default(), |server, addr| {
+            let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            // We don't do options, so clients asking for them fall back to the defaults
+            let config = Config {
+                options: tftp::Options {
+                    block_size: Some(1024),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            // Short, and an exact multiple of the block size
+            for len in [3, 2 * MAX_DATA] {
+                let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                tftp::write("/file", &data, &mut socket, addr, &config).unwrap();
+                assert_eq!(server.backend().0.lock().unwrap()["/file"], data);
+                let read = tftp::read("/file", &mut socket, addr, Mode::Octet, &config).unwrap();
+                assert_eq!(read, data);
+            }
+        });
+    }
+
+    #[test]
+    fn test_errors() {
+        with_server(Files::default(), |_, addr| {
+            let mut socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let config = Config::default();
+            assert!(matches!(
+                tftp::read("/nope", &mut socket, addr, Mode::Octet, &config),
+                Err(Error::ErrorResponse(ErrorCode::NotFound, name)) if name == "/nope"
+            ));
+            assert!(matches!(
+                tftp::write("/ro/file", &[1], &mut socket, addr, &config),
+                Err(Error::ErrorResponse(ErrorCode::AccessViolation, _))
+            ));
+            // Not a request
+            socket
+                .send_to(&Payload::Ack { block: 1 }.pack(), addr)
+                .unwrap();
+            let (reply, _) = tftp::tests::next_packet(&socket);
+            assert!(matches!(
+                reply,
+                Payload::Error {
+                    error_code: ErrorCode::IllegalOp,
+                    ..
+                }
+            ));
+        });
+    }
+
+    #[test]
+    fn test_retransmits() {
+        with_server(Files::default(), |server, addr| {
+            server
+                .backend()
+                .0
+                .lock()
+                .unwrap()
+                .insert("/file".to_owned(), vec![7; MAX_DATA + 1]);
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let rrq = Payload::Read {
+                filename: "/file".to_owned(),
+                mode: Mode::Octet,
+                options: vec![],
+            };
+            client.send_to(&rrq.pack(), addr).unwrap();
+            // Ignoring the first block gets it sent again, from the same TID
+            let (first, tid) = tftp::tests::next_packet(&client);
+            let (again, resent_from) = tftp::tests::next_packet(&client);
+            assert_eq!(tid, resent_from);
+            assert_ne!(tid, addr);
+            assert_eq!(first.pack(), again.pack());
+            client
+                .send_to(&Payload::Ack { block: 1 }.pack(), tid)
+                .unwrap();
+            let (second, _) = tftp::tests::next_packet(&client);
+            assert!(matches!(second, Payload::Data { block: 2, data } if data == [7]));
+            client
+                .send_to(&Payload::Ack { block: 2 }.pack(), tid)
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_resends_despite_stale_acks() {
+        with_server(Files::default(), |server, addr| {
+            server
+                .backend()
+                .0
+                .lock()
+                .unwrap()
+                .insert("/file".to_owned(), vec![7; MAX_DATA + 1]);
+            let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let rrq = Payload::Read {
+                filename: "/file".to_owned(),
+                mode: Mode::Octet,
+                options: vec![],
+            };
+            client.send_to(&rrq.pack(), addr).unwrap();
+            let (_, tid) = tftp::tests::next_packet(&client);
+            // Pretend the first block got lost, and keep sending something stale more often than
+            // the server's timeout. It should still send the block again.
+            client
+                .set_read_timeout(Some(Duration::from_millis(20)))
+                .unwrap();
+            let mut buf = [0; 4 + MAX_DATA];
+            let resent = (0..25).any(|_| {
+                client
+                    .send_to(&Payload::Ack { block: 0 }.pack(), tid)
+                    .unwrap();
+                let Ok((n, _)) = client.recv_from(&mut buf) else {
+                    return false;
+                };
+                matches!(
+                    Payload::unpack(&buf[..n]),
+                    Ok(Payload::Data { block: 1, .. })
+                )
+            });
+            assert!(resent);
+            client
+                .send_to(
+                    &Payload::Error {
+                        error_code: ErrorCode::NotDefined,
+                        error_msg: String::new(),
+                    }
+                    .pack(),
+                    tid,
+                )
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_directory() {
+        let root = std::env::temp_dir().join(format!("tapcp-tftp-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let directory = Directory::new(&root);
+        directory.write("/bitstream.bin", &[1, 2, 3]).unwrap();
+        assert_eq!(fs::read(root.join("bitstream.bin")).unwrap(), [1, 2, 3]);
+        assert_eq!(directory.read("bitstream.bin").unwrap(), [1, 2, 3]);
+        assert!(matches!(
+            directory.read("missing"),
+            Err((ErrorCode::NotFound, _))
+        ));
+        for bad in ["/../etc/passwd", "a/../../b", "/"] {
+            assert!(matches!(
+                directory.check_write(bad),
+                Err((ErrorCode::AccessViolation, _))
+            ));
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
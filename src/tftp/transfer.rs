@@ -0,0 +1,768 @@
+//! A sans-IO TFTP client transfer.
+//!
+//! [`Transfer`] never touches a socket or a clock. The driver feeds it datagrams as they arrive
+//! and tells it when [`Transfer::timeout`] has elapsed without hearing anything, and it hands back
+//! the datagrams that need to go out via [`Transfer::poll_transmit`]. This way the same protocol
+//! logic runs over blocking sockets, async runtimes, simulators, or whatever network stack a
+//! softcore happens to have.
+
+use std::{collections::VecDeque, net::SocketAddr, time::Duration};
+
+use super::{
+    is_behind, Config, Error, ErrorCode, Mode, Options, Payload, MAX_DATA, MIN_BLOCK_SIZE,
+};
+
+/// A datagram the transfer wants sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transmit {
+    pub to: SocketAddr,
+    pub datagram: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Direction {
+    Read {
+        /// The bytes we've received so far
+        output: Vec<u8>,
+        /// The block number of the DATA packet we're waiting on
+        expected: u16,
+        /// The last block we ACKed, if any
+        acked: Option<u16>,
+    },
+    Write {
+        data: Vec<u8>,
+        /// Where the next chunk of `data` starts
+        offset: usize,
+        /// The block we're waiting on an ACK for, with 0 standing in for the WRQ
+        block: u16,
+        /// Whether `block` is the short one that ends the transfer
+        last_block: bool,
+        /// Whether the server has acknowledged the WRQ, as block 0 comes round again on rollover
+        acknowledged: bool,
+    },
+}
+
+/// The state of a single TFTP read or write, from the client's side
+#[derive(Debug)]
+pub struct Transfer {
+    direction: Direction,
+    config: Config,
+    /// Where we sent the request
+    server: SocketAddr,
+    /// The server's transfer ID, once it's answered
+    peer: Option<SocketAddr>,
+    /// The last packet we sent, in case we need to send it again
+    last: Vec<u8>,
+    /// How many times in a row we've resent `last`
+    attempts: usize,
+    /// Whether `last` has gone out since the driver last checked, see [`Self::take_sent`]
+    sent: bool,
+    outgoing: VecDeque<Transmit>,
+    finished: bool,
+    /// Bytes per DATA block, which is 512 unless the server agreed to something else
+    block_size: usize,
+    /// The size of the file, if the server told us
+    transfer_size: Option<u64>,
+}
+
+impl Transfer {
+    fn new(direction: Direction, request: Payload, server: SocketAddr, config: Config) -> Self {
+        let mut transfer = Self {
+            direction,
+            config,
+            server,
+            peer: None,
+            last: vec![],
+            attempts: 0,
+            sent: false,
+            outgoing: VecDeque::new(),
+            finished: false,
+            block_size: MAX_DATA,
+            transfer_size: None,
+        };
+        transfer.send(request.pack());
+        transfer
+    }
+
+    /// Start reading `filename` from the TFTP server at `server`
+    pub fn read(filename: &str, mode: Mode, server: SocketAddr, config: Config) -> Self {
+        let rrq = Payload::Read {
+            filename: filename.to_string(),
+            mode,
+            options: config.options.request(None),
+        };
+        let direction = Direction::Read {
+            output: vec![],
+            expected: 1,
+            acked: None,
+        };
+        Self::new(direction, rrq, server, config)
+    }
+
+    /// Start writing `data` to `filename` on the TFTP server at `server`
+    pub fn write(filename: &str, data: Vec<u8>, server: SocketAddr, config: Config) -> Self {
+        let wrq = Payload::Write {
+            filename: filename.to_string(),
+            mode: Mode::Octet,
+            options: config.options.request(Some(data.len())),
+        };
+        let direction = Direction::Write {
+            data,
+            offset: 0,
+            block: 0,
+            last_block: false,
+            acknowledged: false,
+        };
+        Self::new(direction, wrq, server, config)
+    }
+
+    /// Queue up `datagram` for the other end, remembering it in case we need to resend it
+    fn send(&mut self, datagram: Vec<u8>) {
+        self.attempts = 0;
+        self.last = datagram.clone();
+        self.resend();
+    }
+
+    fn resend(&mut self) {
+        self.sent = true;
+        self.outgoing.push_back(Transmit {
+            to: self.peer.unwrap_or(self.server),
+            datagram: self.last.clone(),
+        });
+    }
+
+    /// The next datagram that needs to go out, if any
+    pub fn poll_transmit(&mut self) -> Option<Transmit> {
+        self.outgoing.pop_front()
+    }
+
+    /// How long the driver should wait after we last sent something before calling
+    /// [`Self::handle_timeout`]. Datagrams that arrive in the meantime don't restart the wait, or a
+    /// steady trickle of strays could keep us from ever resending.
+    pub fn timeout(&self) -> Duration {
+        self.config.timeout
+    }
+
+    /// Whether we've sent or resent our last packet since this was last called, in which case the
+    /// driver should start waiting [`Self::timeout`] afresh
+    pub fn take_sent(&mut self) -> bool {
+        std::mem::take(&mut self.sent)
+    }
+
+    /// Whether the transfer has completed successfully.
+    /// There may still be a final ACK waiting in [`Self::poll_transmit`].
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Whether the server has answered our request, so we know it heard it
+    pub fn is_acknowledged(&self) -> bool {
+        self.peer.is_some()
+    }
+
+    /// Bytes per DATA block, which may change when the server answers
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// The size of the file being read, if we asked for it and the server told us
+    pub fn transfer_size(&self) -> Option<u64> {
+        self.transfer_size
+    }
+
+    /// The bytes we've read so far (all of them, once the transfer is finished).
+    /// Always empty for writes.
+    pub fn into_data(self) -> Vec<u8> {
+        match self.direction {
+            Direction::Read { output, .. } => output,
+            Direction::Write { .. } => vec![],
+        }
+    }
+
+    /// The driver waited [`Self::timeout`] without hearing anything, so resend our last packet
+    /// or give up if we're out of retries
+    pub fn handle_timeout(&mut self) -> Result<(), Error> {
+        if self.finished {
+            return Ok(());
+        }
+        if self.attempts == self.config.retries {
+            return Err(Error::Timeout(self.attempts));
+        }
+        self.attempts += 1;
+        self.resend();
+        Ok(())
+    }
+
+    /// Whether `payload` is how the server would answer our request
+    fn is_first_reply(&self, payload: &Payload) -> bool {
+        match (&self.direction, payload) {
+            (_, Payload::Error { .. }) => true,
+            (_, Payload::OptionAck { .. }) => self.config.options != Options::default(),
+            (Direction::Read { expected, .. }, Payload::Data { block, .. }) => block == expected,
+            (Direction::Write { block: sent, .. }, Payload::Ack { block }) => block == sent,
+            _ => false,
+        }
+    }
+
+    /// Take on the options the server agreed to, which must be ones we asked for
+    fn negotiate(&mut self, options: &[(String, String)]) -> Result<(), Error> {
+        let asked = self.config.options;
+        for (name, value) in options {
+            let bad = || Error::BadOptions(format!("{}={}", name, value));
+            match name.as_str() {
+                "blksize" => {
+                    let size: usize = value.parse().map_err(|_| bad())?;
+                    let limit = asked.block_size.ok_or_else(bad)?;
+                    if size < MIN_BLOCK_SIZE || size > limit as usize {
+                        return Err(bad());
+                    }
+                    self.block_size = size;
+                }
+                "timeout" => {
+                    let seconds: u8 = value.parse().map_err(|_| bad())?;
+                    if asked.timeout != Some(seconds) {
+                        return Err(bad());
+                    }
+                    self.config.timeout = Duration::from_secs(seconds.into());
+                }
+                "tsize" if asked.transfer_size => {
+                    self.transfer_size = Some(value.parse().map_err(|_| bad())?);
+                }
+                _ => return Err(bad()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle an OACK, which the server sends in place of the first DATA of a read or the ACK of
+    /// a write request. Returns the ACK it stands for in a write, so that can carry on as usual.
+    fn handle_option_ack(
+        &mut self,
+        options: &[(String, String)],
+    ) -> Result<Option<Payload>, Error> {
+        let first = match &self.direction {
+            Direction::Read { acked, .. } => acked.is_none(),
+            Direction::Write { acknowledged, .. } => !*acknowledged,
+        };
+        if !first {
+            // The server didn't hear our ACK of its OACK, so send that again
+            if let Direction::Read { acked: Some(0), .. } = self.direction {
+                self.resend();
+            }
+            return Ok(None);
+        }
+        if let Err(e) = self.negotiate(options) {
+            let error = Payload::Error {
+                error_code: ErrorCode::BadOptions,
+                error_msg: e.to_string(),
+            };
+            self.outgoing.push_back(Transmit {
+                to: self.peer.unwrap_or(self.server),
+                datagram: error.pack(),
+            });
+            return Err(e);
+        }
+        match &mut self.direction {
+            Direction::Read { acked, .. } => {
+                *acked = Some(0);
+                self.send(Payload::Ack { block: 0 }.pack());
+                Ok(None)
+            }
+            Direction::Write { .. } => Ok(Some(Payload::Ack { block: 0 })),
+        }
+    }
+
+    /// The server turned down our options with an ERROR 8 (RFC 2347), so ask again without them
+    fn retry_without_options(&mut self) {
+        self.config.options = Options::default();
+        let mut request = Payload::unpack(&self.last).expect("our own request decodes");
+        if let Payload::Read { options, .. } | Payload::Write { options, .. } = &mut request {
+            options.clear();
+        }
+        self.send(request.pack());
+    }
+
+    /// Process a datagram `datagram` that arrived from `from`.
+    ///
+    /// Per RFC 1350, the server answers from a fresh port (its transfer ID), so we lock on to
+    /// whichever port on the server's host first sends the reply we're waiting for. Anything else
+    /// before then is a leftover from an earlier transfer and is ignored. If we asked for options,
+    /// the server either agrees to some of them with an OACK or ignores them all and answers as
+    /// usual, in which case we carry on without them. If it refuses them with an ERROR 8 instead,
+    /// we send the request again without any. From then on, datagrams from anyone else get an
+    /// `UnknownID` error sent back and are otherwise ignored. Datagrams we can't make sense of are
+    /// treated as lost, so the usual resends take care of them.
+    pub fn handle_datagram(&mut self, from: SocketAddr, datagram: &[u8]) -> Result<(), Error> {
+        let stranger = match self.peer {
+            Some(tid) => tid != from,
+            None => from.ip() != self.server.ip(),
+        };
+        if stranger {
+            let stray = Payload::Error {
+                error_code: ErrorCode::UnknownID,
+                error_msg: ErrorCode::UnknownID.to_string(),
+            };
+            self.outgoing.push_back(Transmit {
+                to: from,
+                datagram: stray.pack(),
+            });
+            return Ok(());
+        }
+        let payload = match Payload::unpack(datagram) {
+            Ok(payload) => payload,
+            Err(_) => return Ok(()),
+        };
+        if self.peer.is_none() {
+            if !self.is_first_reply(&payload) {
+                return Ok(());
+            }
+            let refused = matches!(
+                payload,
+                Payload::Error {
+                    error_code: ErrorCode::BadOptions,
+                    ..
+                }
+            );
+            if refused && self.config.options != Options::default() {
+                self.retry_without_options();
+                return Ok(());
+            }
+            self.peer = Some(from);
+        }
+        if self.finished {
+            // The server didn't hear our final ACK, so all we can do is send it again
+            if let (Direction::Read { acked, .. }, Payload::Data { block, .. }) =
+                (&self.direction, &payload)
+            {
+                if Some(*block) == *acked {
+                    self.resend();
+                }
+            }
+            return Ok(());
+        }
+        let payload = match payload {
+            Payload::OptionAck { options } => match self.handle_option_ack(&options)? {
+                Some(payload) => payload,
+                None => return Ok(()),
+            },
+            payload => payload,
+        };
+        match (&mut self.direction, payload) {
+            (
+                Direction::Read {
+                    output,
+                    expected,
+                    acked,
+                },
+                Payload::Data { block, data },
+            ) => {
+                if block == *expected {
+                    // Copy out the bytes
+                    output.extend_from_slice(&data);
+                    *acked = Some(block);
+                    *expected = self.config.rollover.next(block);
+                    // Check end of data condition
+                    self.finished = data.len() < self.block_size;
+                    self.send(Payload::Ack { block }.pack());
+                } else if Some(block) == *acked {
+                    // The server didn't hear our last ACK and sent the block again, so ACK it again
+                    self.resend();
+                }
+                // Anything else is a stale or out of order block that we'll just ignore
+            }
+            (
+                Direction::Write {
+                    data,
+                    offset,
+                    block,
+                    last_block,
+                    acknowledged,
+                },
+                Payload::Ack { block: acked },
+            ) => {
+                if acked == *block {
+                    *acknowledged = true;
+                    if *last_block {
+                        // If we survived this, then we've sent everything we needed to!
+                        self.finished = true;
+                        return Ok(());
+                    }
+                    // Send the next chunk. A transfer whose length is a multiple of the block
+                    // size ends with an empty block, so the server knows we're done.
+                    let end = data.len().min(*offset + self.block_size);
+                    let chunk = data[*offset..end].to_vec();
+                    *offset = end;
+                    *block = self.config.rollover.next(*block);
+                    *last_block = chunk.len() < self.block_size;
+                    let payload = Payload::Data {
+                        block: *block,
+                        data: chunk,
+                    };
+                    self.send(payload.pack());
+                } else if !*acknowledged || !is_behind(acked, *block) {
+                    return Err(Error::BadBlock(acked));
+                }
+                // Otherwise, it's a duplicate ACK of something we've already sent. We must not
+                // resend our data in response, otherwise every subsequent block gets sent twice
+                // (the Sorcerer's Apprentice bug). Our timeout will take care of a lost DATA packet.
+            }
+            (
+                _,
+                Payload::Error {
+                    error_code,
+                    error_msg,
+                },
+            ) => return Err(Error::ErrorResponse(error_code, error_msg)),
+            // Requests, or DATA/ACK going the wrong way
+            _ => return Err(Error::BadOpcode),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tftp::Rollover;
+
+    fn server() -> SocketAddr {
+        "10.0.0.2:69".parse().unwrap()
+    }
+
+    fn tid() -> SocketAddr {
+        "10.0.0.2:4321".parse().unwrap()
+    }
+
+    fn data(block: u16, len: usize) -> Vec<u8> {
+        Payload::Data {
+            block,
+            data: vec![block as u8; len],
+        }
+        .pack()
+    }
+
+    fn ack(block: u16) -> Vec<u8> {
+        Payload::Ack { block }.pack()
+    }
+
+    fn drain(transfer: &mut Transfer) -> Vec<Transmit> {
+        std::iter::from_fn(|| transfer.poll_transmit()).collect()
+    }
+
+    #[test]
+    fn test_read() {
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), Config::default());
+        let rrq = drain(&mut t);
+        assert_eq!(rrq.len(), 1);
+        assert_eq!(rrq[0].to, server());
+        assert_eq!(rrq[0].datagram[..2], [0, 1]);
+        t.handle_datagram(tid(), &data(1, 512)).unwrap();
+        assert_eq!(
+            drain(&mut t),
+            vec![Transmit {
+                to: tid(),
+                datagram: ack(1)
+            }]
+        );
+        assert!(!t.is_finished());
+        t.handle_datagram(tid(), &data(2, 3)).unwrap();
+        assert_eq!(drain(&mut t)[0].datagram, ack(2));
+        assert!(t.is_finished());
+        assert_eq!(t.into_data().len(), 515);
+    }
+
+    #[test]
+    fn test_read_timeouts() {
+        let config = Config {
+            retries: 1,
+            ..Default::default()
+        };
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), config);
+        let rrq = drain(&mut t);
+        t.handle_timeout().unwrap();
+        assert_eq!(drain(&mut t), rrq);
+        let err = t.handle_timeout().unwrap_err();
+        assert!(matches!(err, Error::Timeout(1)));
+    }
+
+    #[test]
+    fn test_read_resends_final_ack() {
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), Config::default());
+        drain(&mut t);
+        t.handle_datagram(tid(), &data(1, 0)).unwrap();
+        assert!(t.is_finished());
+        drain(&mut t);
+        // Our ACK went missing, so the server sends the last block again
+        t.handle_datagram(tid(), &data(1, 0)).unwrap();
+        assert_eq!(drain(&mut t)[0].datagram, ack(1));
+    }
+
+    #[test]
+    fn test_ignores_mangled() {
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), Config::default());
+        drain(&mut t);
+        let mut mangled = data(1, 3);
+        mangled[1] = 0x42;
+        t.handle_datagram(tid(), &mangled).unwrap();
+        t.handle_datagram(tid(), &[0, 3]).unwrap();
+        assert!(drain(&mut t).is_empty());
+        // The resend gets through
+        t.handle_datagram(tid(), &data(1, 3)).unwrap();
+        assert!(t.is_finished());
+    }
+
+    #[test]
+    fn test_write() {
+        let mut t = Transfer::write("/foo", vec![7; 1024], server(), Config::default());
+        drain(&mut t);
+        let mut blocks = vec![];
+        for block in 0..3 {
+            t.handle_datagram(tid(), &ack(block)).unwrap();
+            for tx in drain(&mut t) {
+                assert_eq!(tx.to, tid());
+                match Payload::unpack(&tx.datagram).unwrap() {
+                    Payload::Data { block, data } => blocks.push((block, data.len())),
+                    p => panic!("Expected DATA, got {:?}", p),
+                }
+            }
+        }
+        // A multiple of 512 bytes needs a trailing empty block
+        assert_eq!(blocks, vec![(1, 512), (2, 512), (3, 0)]);
+        assert!(!t.is_finished());
+        t.handle_datagram(tid(), &ack(3)).unwrap();
+        assert!(t.is_finished());
+    }
+
+    #[test]
+    fn test_write_ignores_duplicate_ack() {
+        let mut t = Transfer::write("/foo", vec![7; 600], server(), Config::default());
+        drain(&mut t);
+        t.handle_datagram(tid(), &ack(0)).unwrap();
+        drain(&mut t);
+        t.handle_datagram(tid(), &ack(0)).unwrap();
+        assert!(drain(&mut t).is_empty());
+        assert!(t.handle_datagram(tid(), &ack(5)).is_err());
+    }
+
+    #[test]
+    fn test_stray_tid() {
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), Config::default());
+        drain(&mut t);
+        t.handle_datagram(tid(), &data(1, 512)).unwrap();
+        drain(&mut t);
+        let stray: SocketAddr = "10.0.0.2:9999".parse().unwrap();
+        t.handle_datagram(stray, &data(2, 5)).unwrap();
+        let sent = drain(&mut t);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, stray);
+        assert!(matches!(
+            Payload::unpack(&sent[0].datagram).unwrap(),
+            Payload::Error {
+                error_code: ErrorCode::UnknownID,
+                ..
+            }
+        ));
+        assert!(!t.is_finished());
+    }
+
+    #[test]
+    fn test_ignores_leftovers() {
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), Config::default());
+        drain(&mut t);
+        // A late ACK from an earlier write doesn't get us locked on to its TID
+        let old: SocketAddr = "10.0.0.2:9999".parse().unwrap();
+        t.handle_datagram(old, &ack(3)).unwrap();
+        t.handle_datagram(old, &data(7, 512)).unwrap();
+        assert!(drain(&mut t).is_empty());
+        t.handle_datagram(tid(), &data(1, 3)).unwrap();
+        assert_eq!(drain(&mut t)[0].to, tid());
+        assert!(t.is_finished());
+    }
+
+    #[test]
+    fn test_write_rollover() {
+        let config = Config {
+            rollover: Rollover::One,
+            ..Default::default()
+        };
+        let blocks = u16::MAX as usize + 2;
+        let mut t = Transfer::write("/foo", vec![0; blocks * 512], server(), config);
+        drain(&mut t);
+        let mut block = 0;
+        while !t.is_finished() {
+            t.handle_datagram(tid(), &ack(block)).unwrap();
+            if let Some(tx) = drain(&mut t).pop() {
+                match Payload::unpack(&tx.datagram).unwrap() {
+                    Payload::Data { block: sent, .. } => block = sent,
+                    p => panic!("Expected DATA, got {:?}", p),
+                }
+            }
+        }
+        // 65537 full blocks and an empty one, skipping 0 on the way round
+        assert_eq!(block, 3);
+    }
+
+    #[test]
+    fn test_write_duplicate_ack_after_rollover() {
+        let blocks = u16::MAX as usize + 1;
+        let mut t = Transfer::write(
+            "/foo",
+            vec![0; blocks * 512 + 3],
+            server(),
+            Config::default(),
+        );
+        drain(&mut t);
+        for block in 0..u16::MAX {
+            t.handle_datagram(tid(), &ack(block)).unwrap();
+            drain(&mut t);
+        }
+        t.handle_datagram(tid(), &ack(u16::MAX)).unwrap();
+        let sent = drain(&mut t).pop().unwrap();
+        assert!(matches!(
+            Payload::unpack(&sent.datagram).unwrap(),
+            Payload::Data { block: 0, .. }
+        ));
+        // Block 65535 wrapped round to 0, so a repeat ACK of 65535 is a duplicate, not a bad block
+        t.handle_datagram(tid(), &ack(u16::MAX)).unwrap();
+        assert!(t.poll_transmit().is_none());
+        t.handle_datagram(tid(), &ack(0)).unwrap();
+        t.handle_datagram(tid(), &ack(1)).unwrap();
+        assert!(t.is_finished());
+    }
+
+    fn with_options() -> Config {
+        Config {
+            options: Options {
+                block_size: Some(1024),
+                timeout: Some(2),
+                transfer_size: true,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn oack(options: &[(&str, &str)]) -> Vec<u8> {
+        Payload::OptionAck {
+            options: options
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+        .pack()
+    }
+
+    #[test]
+    fn test_read_options() {
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), with_options());
+        let rrq = Payload::unpack(&drain(&mut t)[0].datagram).unwrap();
+        assert!(matches!(rrq, Payload::Read { options, .. } if options.len() == 3));
+        // The server takes a smaller block size and tells us the size, but ignores the timeout
+        t.handle_datagram(tid(), &oack(&[("blksize", "800"), ("tsize", "900")]))
+            .unwrap();
+        assert_eq!(drain(&mut t)[0].datagram, ack(0));
+        assert_eq!(t.block_size(), 800);
+        assert_eq!(t.transfer_size(), Some(900));
+        assert_eq!(t.timeout(), Config::default().timeout);
+        // Our ACK was lost
+        t.handle_datagram(tid(), &oack(&[("blksize", "800"), ("tsize", "900")]))
+            .unwrap();
+        assert_eq!(drain(&mut t)[0].datagram, ack(0));
+        t.handle_datagram(tid(), &data(1, 800)).unwrap();
+        assert!(!t.is_finished());
+        t.handle_datagram(tid(), &data(2, 100)).unwrap();
+        assert!(t.is_finished());
+        assert_eq!(t.into_data().len(), 900);
+    }
+
+    #[test]
+    fn test_options_ignored() {
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), with_options());
+        drain(&mut t);
+        // A server that doesn't do options just starts sending 512 byte blocks
+        t.handle_datagram(tid(), &data(1, 512)).unwrap();
+        assert!(!t.is_finished());
+        t.handle_datagram(tid(), &data(2, 3)).unwrap();
+        assert!(t.is_finished());
+        assert_eq!(t.block_size(), 512);
+        assert_eq!(t.transfer_size(), None);
+    }
+
+    #[test]
+    fn test_options_refused() {
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), with_options());
+        drain(&mut t);
+        // A server that won't take our options can say so with an ERROR 8
+        let refusal = Payload::Error {
+            error_code: ErrorCode::BadOptions,
+            error_msg: "no thanks".to_owned(),
+        };
+        t.handle_datagram(tid(), &refusal.pack()).unwrap();
+        let sent = drain(&mut t);
+        assert_eq!(sent[0].to, server());
+        let rrq = Payload::unpack(&sent[0].datagram).unwrap();
+        assert!(matches!(rrq, Payload::Read { options, .. } if options.is_empty()));
+        // It answers the new request from a new TID
+        let other: SocketAddr = "10.0.0.2:5678".parse().unwrap();
+        t.handle_datagram(other, &data(1, 3)).unwrap();
+        assert!(t.is_finished());
+        assert_eq!(t.block_size(), 512);
+        assert_eq!(drain(&mut t)[0].to, other);
+    }
+
+    #[test]
+    fn test_write_options() {
+        let mut t = Transfer::write("/foo", vec![7; 1500], server(), with_options());
+        let wrq = Payload::unpack(&drain(&mut t)[0].datagram).unwrap();
+        let tsize = ("tsize".to_owned(), "1500".to_owned());
+        assert!(matches!(wrq, Payload::Write { options, .. } if options.contains(&tsize)));
+        // The OACK stands in for ACK 0
+        t.handle_datagram(tid(), &oack(&[("blksize", "1024"), ("timeout", "2")]))
+            .unwrap();
+        assert_eq!(t.timeout(), Duration::from_secs(2));
+        let mut blocks = vec![];
+        for block in 1..3 {
+            for tx in drain(&mut t) {
+                match Payload::unpack(&tx.datagram).unwrap() {
+                    Payload::Data { block, data } => blocks.push((block, data.len())),
+                    p => panic!("Expected DATA, got {:?}", p),
+                }
+            }
+            t.handle_datagram(tid(), &ack(block)).unwrap();
+        }
+        assert_eq!(blocks, vec![(1, 1024), (2, 476)]);
+        assert!(t.is_finished());
+    }
+
+    #[test]
+    fn test_bad_options() {
+        for options in [
+            &[("blksize", "2048")][..],
+            &[("blksize", "4")],
+            &[("timeout", "5")],
+            &[("windowsize", "4")],
+        ] {
+            let mut t = Transfer::read("/foo", Mode::Octet, server(), with_options());
+            drain(&mut t);
+            assert!(matches!(
+                t.handle_datagram(tid(), &oack(options)),
+                Err(Error::BadOptions(_))
+            ));
+            // The server hears why
+            let sent = drain(&mut t);
+            assert_eq!(sent[0].to, tid());
+            assert!(matches!(
+                Payload::unpack(&sent[0].datagram).unwrap(),
+                Payload::Error {
+                    error_code: ErrorCode::BadOptions,
+                    ..
+                }
+            ));
+        }
+        // We didn't ask for any options, so it can't be an answer to our request
+        let mut t = Transfer::read("/foo", Mode::Octet, server(), Config::default());
+        drain(&mut t);
+        t.handle_datagram(tid(), &oack(&[("blksize", "1024")]))
+            .unwrap();
+        assert!(drain(&mut t).is_empty());
+    }
+}
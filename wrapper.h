@@ -1,4 +0,0 @@
-#ifndef WRAPPER_H_
-#define WRAPPER_H_
-#include "vendor/csl.h"
-#endif // WRAPPER_H_